@@ -0,0 +1,21 @@
+#!/usr/bin/env sh
+# Tests and lints every feature combination CI checks, run from anywhere in the repo
+set -eu
+
+cd "$(dirname "$0")/.."
+
+for features in \
+    "--no-default-features" \
+    "--features bus" \
+    "--no-default-features --features bus" \
+    "--no-default-features --features async,bus" \
+    "--no-default-features --features eh02" \
+    "--no-default-features --features eh02,bus" \
+    "--no-default-features --features eh02,async" \
+    "--all-features"; do
+    echo "== $features"
+    # shellcheck disable=SC2086
+    cargo test $features
+    # shellcheck disable=SC2086
+    cargo clippy --all-targets $features -- -D warnings
+done
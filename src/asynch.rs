@@ -0,0 +1,103 @@
+//! Async `Multiplexer` for `embedded-hal-async` buses
+
+use embedded_hal_async::digital::Wait;
+
+multiplexer_impl!(
+    async; .await;
+    I2c = embedded_hal_async::i2c::I2c, DelayNs = embedded_hal_async::delay::DelayNs
+);
+
+impl<I2C, C> Multiplexer<I2C, C>
+where
+    I2C: embedded_hal_async::i2c::I2c,
+    C: Interrupts,
+{
+    /// Waits for a falling edge on the active low INT output, then reads which ports have
+    /// their interrupt input asserted. INT stays low while any interrupt is asserted, so one
+    /// that is already pending needs [`interrupts`](Self::interrupts) instead. An interrupt
+    /// that clears before the read returns no ports
+    pub async fn wait_for_interrupt(
+        &mut self,
+        int: &mut impl Wait,
+    ) -> Result<C::Ports<bool>, I2C::Error> {
+        int.wait_for_falling_edge()
+            .await
+            .map_err(|_| MultiplexerError::PinError)?;
+        self.interrupts().await
+    }
+}
+
+#[cfg(test)]
+multiplexer_tests!(.await; delay_ns = async_delay_ns);
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    extern crate std;
+    use crate::asynch::Multiplexer;
+    use crate::prelude::{MultiplexerError, Pca9543, Pca9545};
+    use alloc::vec;
+    use embassy_futures::block_on;
+    use embedded_hal_mock::eh1::digital::{Edge, Mock as PinMock, Transaction as PinTransaction};
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+    use embedded_hal_mock::eh1::MockError;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn wait_for_interrupt() {
+        let i2c = Mock::new(&[
+            Transaction::read(0x70, vec![0b0100_0000]),
+            // Interrupt already cleared by the time the register is read
+            Transaction::read(0x70, vec![0b0000_0000]),
+        ]);
+        let mut int = PinMock::new(&[
+            PinTransaction::wait_for_edge(Edge::Falling),
+            PinTransaction::wait_for_edge(Edge::Falling),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9545);
+        assert_eq!(
+            block_on(multiplexer.wait_for_interrupt(&mut int)),
+            Ok([false, false, true, false])
+        );
+        assert_eq!(
+            block_on(multiplexer.wait_for_interrupt(&mut int)),
+            Ok([false; 4])
+        );
+        int.done();
+        multiplexer.done();
+    }
+
+    #[test]
+    fn wait_for_interrupt_pin_error() {
+        let i2c = Mock::new(&[]);
+        let mut int = PinMock::new(&[PinTransaction::wait_for_edge(Edge::Falling)
+            .with_error(MockError::Io(ErrorKind::Other))]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9543);
+        assert_eq!(
+            block_on(multiplexer.wait_for_interrupt(&mut int)),
+            Err(MultiplexerError::PinError)
+        );
+        int.done();
+        multiplexer.done();
+    }
+
+    #[test]
+    fn passthrough() {
+        use embedded_hal_async::i2c::I2c;
+
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write_read(0x48, vec![0x01], vec![0x02]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c);
+        let mut read = [0];
+        assert_eq!(
+            block_on(multiplexer.write_read(0x48, &[0x01], &mut read)),
+            Err(MultiplexerError::AmbiguousRouting)
+        );
+        assert!(block_on(multiplexer.select_only(2)).is_ok());
+        assert!(block_on(multiplexer.write_read(0x48, &[0x01], &mut read)).is_ok());
+        assert_eq!(read, [0x02]);
+        multiplexer.done();
+    }
+}
@@ -0,0 +1,1074 @@
+//! Blocking `Multiplexer` for `embedded-hal` buses
+
+multiplexer_impl!(
+    ; ;
+    I2c = embedded_hal::i2c::I2c, DelayNs = embedded_hal::delay::DelayNs
+);
+
+use crate::error::ForEachError;
+
+/// Result of one port visited by [`Multiplexer::map_ports`]
+pub type PortResult<T, E, I2cError> = core::result::Result<T, ForEachError<E, I2cError>>;
+
+/// Results of every port visited by [`Multiplexer::map_ports`]
+pub type PortResults<C, T, E, I2cError> = <C as Chip>::Ports<PortResult<T, E, I2cError>>;
+
+impl<I2C> Multiplexer<I2C>
+where
+    I2C: embedded_hal::i2c::I2c,
+{
+    /// Creates a multiplexer on an embedded-hal 1.0 bus, same as [`new`](Self::new)
+    pub fn new_eh1(i2c: I2C) -> Self {
+        Self::new(i2c)
+    }
+}
+
+#[cfg(feature = "eh02")]
+impl<I2C> Multiplexer<crate::eh02::Eh02Bus<I2C>>
+where
+    crate::eh02::Eh02Bus<I2C>: embedded_hal::i2c::I2c,
+{
+    /// Creates a multiplexer on an embedded-hal 0.2 bus
+    pub fn new_eh02(i2c: I2C) -> Self {
+        Self::new(crate::eh02::Eh02Bus(i2c))
+    }
+}
+
+impl<I2C, C> Multiplexer<I2C, C>
+where
+    I2C: embedded_hal::i2c::I2c,
+    C: Chip,
+{
+    /// Enables `port` until the returned guard is dropped or finished, which writes the
+    /// previous port states back
+    pub fn open(&mut self, port: u8) -> Result<PortGuard<'_, I2C, C>, I2C::Error> {
+        let previous = self.state;
+        self.set_port(port, true)?;
+        self.commit()?;
+        Ok(PortGuard {
+            multiplexer: self,
+            previous,
+            finished: false,
+        })
+    }
+
+    /// Enables `port`, runs `f` with a bus routed through it and writes the previous port states
+    /// back afterwards, whatever `f` returned
+    pub fn with_port_scope<T>(
+        &mut self,
+        port: u8,
+        f: impl FnOnce(&mut PortGuard<'_, I2C, C>) -> T,
+    ) -> Result<T, I2C::Error> {
+        let mut guard = self.open(port)?;
+        let value = f(&mut guard);
+        guard.finish()?;
+        Ok(value)
+    }
+
+    /// Enables each port on its own in order and runs `f` with a bus routed through it, then
+    /// writes the previous port states back. Stops at the first error
+    pub fn for_each_port<E>(
+        &mut self,
+        mut f: impl FnMut(u8, &mut PortAccess<'_, I2C>) -> core::result::Result<(), E>,
+    ) -> core::result::Result<(), ForEachError<E, I2C::Error>> {
+        let previous = self.state;
+        let result = (0..C::CHANNELS).try_for_each(|port| self.visit(port, &mut f));
+        let restored = self.write_mask(previous);
+        result?;
+        restored.map_err(ForEachError::Restore)
+    }
+
+    /// Same as [`for_each_port`](Self::for_each_port) but continues past errors and returns
+    /// the result of every port
+    pub fn map_ports<T, E>(
+        &mut self,
+        mut f: impl FnMut(u8, &mut PortAccess<'_, I2C>) -> core::result::Result<T, E>,
+    ) -> Result<PortResults<C, T, E, I2C::Error>, I2C::Error> {
+        let previous = self.state;
+        let results = PortArray::from_fn(|port| self.visit(port as u8, &mut f));
+        self.write_mask(previous)?;
+        Ok(results)
+    }
+
+    fn visit<T, E>(
+        &mut self,
+        port: u8,
+        f: &mut impl FnMut(u8, &mut PortAccess<'_, I2C>) -> core::result::Result<T, E>,
+    ) -> core::result::Result<T, ForEachError<E, I2C::Error>> {
+        self.select_only(port)
+            .map_err(|error| ForEachError::Select { port, error })?;
+        f(
+            port,
+            &mut PortAccess {
+                i2c: &mut self.i2c,
+                port,
+            },
+        )
+        .map_err(|error| ForEachError::Port { port, error })
+    }
+}
+
+/// Bus routed through the port currently visited by [`Multiplexer::for_each_port`]
+pub struct PortAccess<'a, I2C> {
+    i2c: &'a mut I2C,
+    port: u8,
+}
+
+impl<I2C> PortAccess<'_, I2C> {
+    /// Port the bus is routed through
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+}
+
+impl<I2C> embedded_hal::i2c::ErrorType for PortAccess<'_, I2C>
+where
+    I2C: embedded_hal::i2c::I2c,
+{
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<I2C> embedded_hal::i2c::I2c for PortAccess<'_, I2C>
+where
+    I2C: embedded_hal::i2c::I2c,
+{
+    fn read(&mut self, address: u8, read: &mut [u8]) -> core::result::Result<(), Self::Error> {
+        self.i2c
+            .read(address, read)
+            .map_err(MultiplexerError::Transfer)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> core::result::Result<(), Self::Error> {
+        self.i2c
+            .write(address, write)
+            .map_err(MultiplexerError::Transfer)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> core::result::Result<(), Self::Error> {
+        self.i2c
+            .write_read(address, write, read)
+            .map_err(MultiplexerError::Transfer)
+    }
+
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> core::result::Result<(), Self::Error> {
+        self.i2c
+            .transaction(address, operations)
+            .map_err(MultiplexerError::Transfer)
+    }
+}
+
+/// Port enabled by [`Multiplexer::open`], transfers through the guard go to the devices behind
+/// the multiplexer
+///
+/// If writing the previous port states back fails while dropping, they are left cached as a
+/// pending change, [`Multiplexer::is_dirty`] reports it and [`Multiplexer::commit`] retries it
+pub struct PortGuard<'a, I2C, C>
+where
+    I2C: embedded_hal::i2c::I2c,
+    C: Chip,
+{
+    multiplexer: &'a mut Multiplexer<I2C, C>,
+    previous: u8,
+    finished: bool,
+}
+
+impl<I2C, C> PortGuard<'_, I2C, C>
+where
+    I2C: embedded_hal::i2c::I2c,
+    C: Chip,
+{
+    /// Writes the previous port states back
+    pub fn finish(mut self) -> Result<(), I2C::Error> {
+        self.finished = true;
+        self.restore()
+    }
+
+    fn restore(&mut self) -> Result<(), I2C::Error> {
+        let result = self.multiplexer.write_state(self.previous);
+        if result.is_err() {
+            self.multiplexer.state = self.previous;
+            self.multiplexer.dirty = true;
+        }
+        result?;
+        self.multiplexer.commit()
+    }
+}
+
+impl<I2C, C> Drop for PortGuard<'_, I2C, C>
+where
+    I2C: embedded_hal::i2c::I2c,
+    C: Chip,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.restore();
+        }
+    }
+}
+
+impl<I2C, C> embedded_hal::i2c::ErrorType for PortGuard<'_, I2C, C>
+where
+    I2C: embedded_hal::i2c::I2c,
+    C: Chip,
+{
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<I2C, C> embedded_hal::i2c::I2c for PortGuard<'_, I2C, C>
+where
+    I2C: embedded_hal::i2c::I2c,
+    C: Chip,
+{
+    fn read(&mut self, address: u8, read: &mut [u8]) -> core::result::Result<(), Self::Error> {
+        self.multiplexer
+            .i2c
+            .read(address, read)
+            .map_err(MultiplexerError::Transfer)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> core::result::Result<(), Self::Error> {
+        self.multiplexer
+            .i2c
+            .write(address, write)
+            .map_err(MultiplexerError::Transfer)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> core::result::Result<(), Self::Error> {
+        self.multiplexer
+            .i2c
+            .write_read(address, write, read)
+            .map_err(MultiplexerError::Transfer)
+    }
+
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> core::result::Result<(), Self::Error> {
+        self.multiplexer
+            .i2c
+            .transaction(address, operations)
+            .map_err(MultiplexerError::Transfer)
+    }
+}
+
+#[cfg(test)]
+multiplexer_tests!(; delay_ns = delay_ns);
+
+#[cfg(all(test, feature = "eh02"))]
+multiplexer_tests!(
+    shared_eh02; ;
+    delay_ns = delay_ns,
+    bus = crate::eh02::test::eh02_bus,
+    error = crate::error::Eh02BusError::Bus,
+    eh1 = any()
+);
+
+#[cfg(all(test, feature = "eh02"))]
+impl<C: Chip>
+    Multiplexer<
+        crate::eh02::Eh02Bus<crate::eh02::test::Eh1As02<embedded_hal_mock::eh1::i2c::Mock>>,
+        C,
+    >
+{
+    pub(crate) fn done(mut self) {
+        self.i2c.done();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    extern crate std;
+    use crate::chip::ChannelEncoder;
+    use crate::error::ForEachError;
+    use crate::prelude::*;
+    use alloc::vec;
+    use embedded_hal::i2c::{ErrorKind, I2c, NoAcknowledgeSource, Operation};
+    use embedded_hal_mock::eh1::delay::{CheckedDelay, Transaction as DelayTransaction};
+    use embedded_hal_mock::eh1::digital::{
+        Mock as PinMock, State as PinState, Transaction as PinTransaction,
+    };
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+    use embedded_hal_mock::eh1::MockError;
+    use rstest::*;
+
+    #[rstest]
+    #[case([true;4], 0b0000_1111)]
+    #[case([false;4], 0b0000_0000)]
+    #[case([true, false, true, false], 0b0000_0101)]
+    fn setup_ports(#[case] ports: [bool; 4], #[case] result: u8) {
+        assert_eq!(crate::maybe_async::port_code::<Pca9546>(ports), result)
+    }
+
+    #[rstest]
+    #[case([true;3], 0b0111_0111)]
+    #[case([false;3], 0b0111_0000)]
+    #[case([true, false, false], 0b0111_0001)]
+    #[case([false, true, false], 0b0111_0010)]
+    #[case([true, false, true], 0b0111_0101)]
+    fn setup_address(#[case] addr: [bool; 3], #[case] result: u8) {
+        let i2c = Mock::new(&[]);
+        let multiplexer = Multiplexer::new(i2c).with_address_pins(addr[0], addr[1], addr[2]);
+        assert_eq!(multiplexer.address, result);
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(0, 0b0000_0001)]
+    #[case(3, 0b0000_1000)]
+    #[case(4, 0b0001_0000)]
+    #[case(7, 0b1000_0000)]
+    fn eight_channel_port(#[case] port: u8, #[case] result: u8) {
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![result])]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Tca9548);
+        assert!(multiplexer.set_port(port, true).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn eight_channel_ports() {
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![0b1010_0101])]);
+        let multiplexer = Multiplexer::new(i2c)
+            .with_chip(Tca9548)
+            .with_ports([true, false, true, false, false, true, false, true])
+            .unwrap();
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(4)]
+    #[case(8)]
+    fn out_of_range_port(#[case] port: u8) {
+        let i2c = Mock::new(&[]);
+        let mut multiplexer = Multiplexer::new(i2c);
+        assert_eq!(
+            multiplexer.set_port(port, true),
+            Err(MultiplexerError::InvalidPort(port))
+        );
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(0, 0b0000_0100)]
+    #[case(1, 0b0000_0101)]
+    #[case(2, 0b0000_0110)]
+    #[case(3, 0b0000_0111)]
+    fn single_channel_port(#[case] port: u8, #[case] result: u8) {
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![result])]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9544);
+        assert!(multiplexer.set_port(port, true).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn single_channel_switching() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x70, vec![0b0000_0110]),
+            Transaction::write(0x70, vec![0b0000_0110]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9544);
+        // Enabling port 2 implicitly disables port 0
+        assert!(multiplexer.set_port(0, true).is_ok());
+        assert!(multiplexer.set_port(2, true).is_ok());
+        // Disabling a port that is not selected keeps the current one
+        assert!(multiplexer.set_port(0, false).is_ok());
+        assert!(multiplexer.set_port(2, false).is_ok());
+        assert!(multiplexer.set_ports([false; 4]).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn single_channel_multiple_ports() {
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0101])]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9544);
+        assert_eq!(
+            multiplexer.set_ports([true, false, true, false]),
+            Err(MultiplexerError::UnsupportedPorts(0b0000_0101))
+        );
+        assert!(multiplexer.set_ports([false, true, false, false]).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn single_channel_disabled() {
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0000])]);
+        let multiplexer = Multiplexer::new(i2c)
+            .with_chip(Pca9544)
+            .with_ports_disabled()
+            .unwrap();
+        multiplexer.done();
+    }
+
+    #[test]
+    fn control_register() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0x10, 0b0000_0010]),
+            Transaction::write_read(0x70, vec![0x10], vec![0b0100_0010]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c)
+            .with_chip(Pca9545)
+            .with_control_register(0x10);
+        assert!(multiplexer.set_port(1, true).is_ok());
+        assert_eq!(multiplexer.interrupts(), Ok([false, false, true, false]));
+        multiplexer.done();
+    }
+
+    #[test]
+    fn borrowed_bus() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x20, vec![0x05]),
+        ]);
+        {
+            let mut multiplexer = Multiplexer::new(&mut i2c);
+            assert!(multiplexer.set_port(0, true).is_ok());
+        }
+        // The bus is usable again once the multiplexer is dropped
+        assert!(embedded_hal::i2c::I2c::write(&mut i2c, 0x20, &[0x05]).is_ok());
+        i2c.done();
+    }
+
+    #[cfg(feature = "eh02")]
+    #[test]
+    fn eh02_bus() {
+        use crate::eh02::test::Eh1As02;
+
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x70, vec![0b0000_1010]),
+            Transaction::read(0x70, vec![0b1000_1010]),
+        ]);
+        let mut multiplexer = Multiplexer::new_eh02(Eh1As02(i2c)).with_chip(Pca9545);
+        assert!(multiplexer.set_port(1, true).is_ok());
+        assert!(multiplexer.set_port(3, true).is_ok());
+        assert_eq!(multiplexer.interrupts(), Ok([false, false, false, true]));
+        multiplexer.done();
+    }
+
+    #[cfg(feature = "eh02")]
+    #[test]
+    fn eh02_too_many_operations() {
+        use crate::eh02::{test::Eh1As02, MAX_TRANSACTION_OPERATIONS};
+
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0001])]);
+        let mut multiplexer = Multiplexer::new_eh02(Eh1As02(i2c));
+        let mut operations: [_; MAX_TRANSACTION_OPERATIONS + 1] =
+            core::array::from_fn(|_| Operation::Write(&[0x01]));
+        assert_eq!(
+            multiplexer.exec_on(0, 0x20, &mut operations),
+            Err(MultiplexerError::Transfer(Eh02BusError::TooManyOperations))
+        );
+        multiplexer.done();
+    }
+
+    #[test]
+    fn deselect_byte() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0111]),
+            Transaction::write(0x70, vec![0b0000_0011]),
+            Transaction::write(0x70, vec![0b0000_0110]),
+            Transaction::write(0x70, vec![0b0000_0011]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c)
+            .with_chip(Pca9544)
+            .with_deselect_byte(0b0000_0011);
+        assert!(multiplexer.set_port(3, true).is_ok());
+        assert!(multiplexer.set_port(3, false).is_ok());
+        assert!(multiplexer.set_port(2, true).is_ok());
+        let multiplexer = multiplexer.with_ports_disabled().unwrap();
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(0b0000_0000, [false; 4])]
+    #[case(0b1111_0000, [true; 4])]
+    #[case(0b0101_0011, [true, false, true, false])]
+    #[case(0b1000_1111, [false, false, false, true])]
+    fn interrupts(#[case] code: u8, #[case] result: [bool; 4]) {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::read(0x70, vec![code]),
+            Transaction::write(0x70, vec![0b0000_0011]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9545);
+        assert!(multiplexer.set_port(1, true).is_ok());
+        assert_eq!(multiplexer.interrupts(), Ok(result));
+        // Reading the interrupts doesn't change the port states
+        assert!(multiplexer.set_port(0, true).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn hard_reset() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0101]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+        ]);
+        let mut pin = PinMock::new(&[
+            PinTransaction::set(PinState::Low),
+            PinTransaction::set(PinState::High),
+        ]);
+        let mut delay = CheckedDelay::new(&[
+            DelayTransaction::delay_ns(6),
+            DelayTransaction::delay_ns(500),
+        ]);
+
+        let mut multiplexer = Multiplexer::new(i2c)
+            .with_ports([true, false, true, false])
+            .unwrap();
+        assert!(multiplexer.hard_reset(&mut pin, &mut delay).is_ok());
+        // Ports 0 and 2 were disabled by the reset
+        assert!(multiplexer.set_port(1, true).is_ok());
+
+        multiplexer.done();
+        pin.done();
+        delay.done();
+    }
+
+    #[test]
+    fn hard_reset_pin_error() {
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0001])]);
+        let mut pin =
+            PinMock::new(&[PinTransaction::set(PinState::Low)
+                .with_error(MockError::Io(std::io::ErrorKind::Other))]);
+        let mut delay = CheckedDelay::new(&[]);
+
+        let mut multiplexer = Multiplexer::new(i2c);
+        assert_eq!(
+            multiplexer.hard_reset(&mut pin, &mut delay),
+            Err(MultiplexerError::PinError)
+        );
+        assert!(multiplexer.set_port(0, true).is_ok());
+
+        multiplexer.done();
+        pin.done();
+        delay.done();
+    }
+
+    #[rstest]
+    #[case(0, 0b0000_0001)]
+    #[case(1, 0b0000_0010)]
+    fn two_channel_port(#[case] port: u8, #[case] result: u8) {
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![result])]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9543);
+        assert!(multiplexer.set_port(port, true).is_ok());
+        assert_eq!(
+            multiplexer.set_port(2, true),
+            Err(MultiplexerError::InvalidPort(2))
+        );
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(0b0001_0000, [true, false])]
+    #[case(0b0010_0011, [false, true])]
+    #[case(0b1100_0000, [false, false])]
+    fn two_channel_interrupts(#[case] code: u8, #[case] result: [bool; 2]) {
+        let i2c = Mock::new(&[Transaction::read(0x70, vec![code])]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9543);
+        assert_eq!(multiplexer.interrupts(), Ok(result));
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(0, 0b0000_0100)]
+    #[case(1, 0b0000_0101)]
+    fn fixed_address_mux_port(#[case] port: u8, #[case] result: u8) {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![result]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9540);
+        assert!(multiplexer.set_port(port, true).is_ok());
+        assert_eq!(
+            multiplexer.set_port(2, true),
+            Err(MultiplexerError::InvalidPort(2))
+        );
+        assert!(multiplexer.set_ports([false; 2]).is_ok());
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(0, 0b0000_1000)]
+    #[case(5, 0b0000_1101)]
+    #[case(7, 0b0000_1111)]
+    fn eight_channel_mux_port(#[case] port: u8, #[case] result: u8) {
+        let i2c = Mock::new(&[Transaction::write(0x70, vec![result])]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9547);
+        assert!(multiplexer.set_port(port, true).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn eight_channel_mux_power_on_state() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9547);
+        // Port 0 is enabled at power-up so disabling another port keeps it selected
+        assert!(multiplexer.set_port(3, false).is_ok());
+        assert!(multiplexer.set_port(0, false).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn two_channel_mux() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x74, vec![0b0000_0100]),
+            Transaction::write(0x74, vec![0b0000_0101]),
+            Transaction::read(0x74, vec![0b0010_0101]),
+            Transaction::write(0x74, vec![0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9542).with_address(0x74);
+        assert!(multiplexer.set_port(0, true).is_ok());
+        assert!(multiplexer.set_port(1, true).is_ok());
+        assert_eq!(multiplexer.interrupts(), Ok([false, true]));
+        assert!(multiplexer.set_port(1, false).is_ok());
+        assert_eq!(
+            multiplexer.set_port(2, true),
+            Err(MultiplexerError::InvalidPort(2))
+        );
+        multiplexer.done();
+    }
+
+    #[test]
+    fn device_id() {
+        let i2c = Mock::new(&[
+            Transaction::write_read(0x7C, vec![0xE2], vec![0x00, 0x12, 0x3A]),
+            Transaction::write(0x71, vec![0b0000_0100]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9846).with_address(0x71);
+        assert_eq!(
+            multiplexer.device_id(),
+            Ok(DeviceId {
+                manufacturer: 0x001,
+                part: 0x047,
+                revision: 0b010,
+            })
+        );
+        assert!(multiplexer.set_port(2, true).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn device_id_error() {
+        let i2c = Mock::new(&[Transaction::write_read(0x7C, vec![0xE0], vec![0, 0, 0])
+            .with_error(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address))]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9846);
+        assert_eq!(
+            multiplexer.device_id(),
+            Err(MultiplexerError::DeviceIdError(ErrorKind::NoAcknowledge(
+                NoAcknowledgeSource::Address
+            )))
+        );
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(0, 0b0000_0100)]
+    #[case(3, 0b0000_0111)]
+    fn four_channel_mux_with_device_id(#[case] port: u8, #[case] result: u8) {
+        let id = DeviceId {
+            manufacturer: 0x000,
+            part: 0x047,
+            revision: 0,
+        };
+        let i2c = Mock::new(&[
+            Transaction::write_read(0x7C, vec![0xE0], vec![0x00, 0x02, 0x3B]),
+            Transaction::write(0x70, vec![result]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c)
+            .with_chip(Pca9849)
+            .with_device_id(id)
+            .unwrap();
+        assert!(multiplexer.set_port(port, true).is_ok());
+        assert!(multiplexer.set_port(port, false).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn device_id_mismatch() {
+        let mut i2c = Mock::new(&[Transaction::write_read(
+            0x7C,
+            vec![0xE0],
+            vec![0x00, 0x12, 0x38],
+        )]);
+        let expected = DeviceId {
+            manufacturer: 0x000,
+            part: 0x047,
+            revision: 0,
+        };
+        let found = DeviceId {
+            manufacturer: 0x001,
+            part: 0x047,
+            revision: 0,
+        };
+        assert_eq!(
+            Multiplexer::new(i2c.clone())
+                .with_chip(Pca9849)
+                .with_device_id(expected)
+                .map(|_| ()),
+            Err(MultiplexerError::DeviceIdMismatch(found))
+        );
+        i2c.done();
+    }
+
+    #[rstest]
+    #[case(0b0000_0000, None)]
+    #[case(0b0000_0011, None)]
+    #[case(0b0000_0100, Some(0))]
+    #[case(0b0000_0101, Some(1))]
+    #[case(0b0000_0110, Some(2))]
+    #[case(0b0000_0111, Some(3))]
+    fn status_selected(#[case] code: u8, #[case] selected: Option<u8>) {
+        for interrupts in 0..16 {
+            let status = Tca9544Status::from(interrupts << 4 | code);
+            assert_eq!(status.selected, selected);
+        }
+    }
+
+    #[test]
+    fn status_interrupts() {
+        for interrupts in 0..16u8 {
+            let status = Tca9544Status::from(interrupts << 4 | 0b0000_0101);
+            for port in 0..4 {
+                assert_eq!(status.interrupts[port], interrupts & (1 << port) != 0);
+            }
+            assert_eq!(
+                status.next_interrupting_port(),
+                (interrupts != 0).then(|| interrupts.trailing_zeros() as u8)
+            );
+        }
+    }
+
+    #[test]
+    fn read_status() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0110]),
+            Transaction::read(0x70, vec![0b1010_0110]),
+            Transaction::read(0x70, vec![0b1010_0110]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9544);
+        assert!(multiplexer.set_port(2, true).is_ok());
+        assert_eq!(
+            multiplexer.read_status(),
+            Ok(Tca9544Status {
+                selected: Some(2),
+                interrupts: [false, true, false, true],
+            })
+        );
+        assert_eq!(multiplexer.next_interrupting_port(), Ok(Some(1)));
+        multiplexer.done();
+    }
+
+    /// Active low switch, a cleared bit enables the port
+    struct InvertedEncoder;
+
+    impl ChannelEncoder for InvertedEncoder {
+        fn select(port: u8) -> u8 {
+            !(1 << port) & 0b0000_1111
+        }
+
+        fn select_mask(mask: u8) -> Option<u8> {
+            Some(!mask & 0b0000_1111)
+        }
+
+        fn deselect() -> u8 {
+            0b0000_1111
+        }
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    struct InvertedSwitch;
+
+    impl Chip for InvertedSwitch {
+        type Ports<T> = [T; 4];
+        type Encoder = InvertedEncoder;
+    }
+
+    #[test]
+    fn custom_encoder() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_1110]),
+            Transaction::write(0x70, vec![0b0000_0110]),
+            Transaction::write(0x70, vec![0b0000_1111]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(InvertedSwitch);
+        assert!(multiplexer.set_port(0, true).is_ok());
+        assert!(multiplexer.set_port(3, true).is_ok());
+        assert!(multiplexer.set_ports([false; 4]).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn port_guard() {
+        use embedded_hal::i2c::I2c;
+
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x70, vec![0b0000_0101]),
+            Transaction::transaction_start(0x20),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::transaction_end(0x20),
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x70, vec![0b0000_0011]),
+            Transaction::write(0x70, vec![0b0000_0001]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c);
+        assert!(multiplexer.set_port(0, true).is_ok());
+        {
+            let mut guard = multiplexer.open(2).unwrap();
+            assert!(guard
+                .transaction(0x20, &mut [Operation::Write(&[0x01])])
+                .is_ok());
+        }
+        assert_eq!(multiplexer.mask(), 0b0000_0001);
+        let guard = multiplexer.open(1).unwrap();
+        assert!(guard.finish().is_ok());
+        assert_eq!(multiplexer.mask(), 0b0000_0001);
+        multiplexer.done();
+    }
+
+    #[test]
+    fn port_guard_restore_error() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x70, vec![0b0000_0000]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x70, vec![0b0000_0000]).with_error(ErrorKind::Bus),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c);
+        drop(multiplexer.open(3).unwrap());
+        assert!(multiplexer.is_dirty());
+        assert_eq!(multiplexer.port_state(3), Ok(PortState::Unknown));
+        assert!(multiplexer.commit().is_ok());
+        assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+        let guard = multiplexer.open(3).unwrap();
+        assert_eq!(
+            guard.finish(),
+            Err(MultiplexerError::Select(ErrorKind::Bus))
+        );
+        multiplexer.done();
+    }
+
+    #[test]
+    fn port_scope() {
+        use embedded_hal::i2c::I2c;
+
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_assumed_state([false; 4]);
+        assert_eq!(
+            multiplexer.with_port_scope(1, |bus| bus.write(0x20, &[0x01])),
+            Ok(Ok(()))
+        );
+        assert_eq!(
+            multiplexer.with_port_scope(1, |bus| bus.write(0x20, &[0x01])),
+            Ok(Err(MultiplexerError::Transfer(ErrorKind::Bus)))
+        );
+        assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+        assert_eq!(
+            multiplexer.with_port_scope(4, |_| ()),
+            Err(MultiplexerError::InvalidPort(4))
+        );
+        multiplexer.done();
+    }
+
+    #[test]
+    fn passthrough() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x48, vec![0x01]),
+            Transaction::read(0x48, vec![0x02]),
+            Transaction::write_read(0x48, vec![0x03], vec![0x04]),
+            Transaction::transaction_start(0x48),
+            Transaction::write(0x48, vec![0x05]),
+            Transaction::transaction_end(0x48),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c);
+        assert!(multiplexer.select_only(1).is_ok());
+        // Handed to a driver as a plain bus
+        fn driver<I: I2c>(i2c: &mut I) -> Result<u8, I::Error> {
+            let mut read = [0];
+            i2c.write(0x48, &[0x01])?;
+            i2c.read(0x48, &mut read)?;
+            i2c.write_read(0x48, &[0x03], &mut read)?;
+            i2c.transaction(0x48, &mut [Operation::Write(&[0x05])])?;
+            Ok(read[0])
+        }
+        assert_eq!(driver(&mut multiplexer).unwrap(), 0x04);
+        multiplexer.done();
+    }
+
+    #[rstest]
+    #[case(None)]
+    #[case(Some(0b0000_0000))]
+    #[case(Some(0b0000_0101))]
+    fn passthrough_ambiguous(#[case] mask: Option<u8>) {
+        let i2c = Mock::new(&match mask {
+            Some(mask) => vec![Transaction::write(0x70, vec![mask])],
+            None => vec![],
+        });
+        let mut multiplexer = Multiplexer::new(i2c);
+        if let Some(mask) = mask {
+            assert!(multiplexer.set_mask(mask).is_ok());
+        }
+        assert_eq!(
+            multiplexer.write(0x48, &[0x01]),
+            Err(MultiplexerError::AmbiguousRouting)
+        );
+        multiplexer.done();
+    }
+
+    #[test]
+    fn passthrough_lazy() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x48, vec![0x01]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_lazy_writes();
+        assert!(multiplexer.set_port(3, true).is_ok());
+        // The pending change is written before the transfer
+        assert!(multiplexer.write(0x48, &[0x01]).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn for_each_port() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0101]),
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x48, vec![0x00]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x48, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x48, vec![0x02]),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x48, vec![0x03]),
+            Transaction::write(0x70, vec![0b0000_0101]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_mask(0b0000_0101).unwrap();
+        assert!(multiplexer
+            .for_each_port(|port, bus| bus.write(0x48, &[port]))
+            .is_ok());
+        assert_eq!(multiplexer.mask(), 0b0000_0101);
+        multiplexer.done();
+    }
+
+    #[test]
+    fn for_each_port_error() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c);
+        assert_eq!(
+            multiplexer.for_each_port(|_, _| Ok::<_, ()>(())),
+            Err(ForEachError::Select {
+                port: 1,
+                error: MultiplexerError::Select(ErrorKind::Bus)
+            })
+        );
+        assert_eq!(
+            multiplexer.for_each_port(|port, _| match port {
+                1 => Err("no sensor"),
+                _ => Ok(()),
+            }),
+            Err(ForEachError::Port {
+                port: 1,
+                error: "no sensor"
+            })
+        );
+        assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+        multiplexer.done();
+    }
+
+    #[test]
+    fn map_ports() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::read(0x48, vec![0x10]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::read(0x48, vec![0x11]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0100]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::read(0x48, vec![0x13]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c);
+        let results = multiplexer.map_ports(|_, bus| {
+            let mut read = [0];
+            bus.read(0x48, &mut read).map(|_| read[0])
+        });
+        assert_eq!(
+            results,
+            Ok([
+                Ok(0x10),
+                Err(ForEachError::Port {
+                    port: 1,
+                    error: MultiplexerError::Transfer(ErrorKind::Bus)
+                }),
+                Err(ForEachError::Select {
+                    port: 2,
+                    error: MultiplexerError::Select(ErrorKind::Bus)
+                }),
+                Ok(0x13),
+            ])
+        );
+        multiplexer.done();
+    }
+
+    #[cfg(feature = "bus")]
+    #[test]
+    fn into_bus() {
+        use embedded_hal::i2c::I2c;
+
+        let i2c = Mock::new(&[
+            Transaction::write(0x72, vec![0x01, 0b0000_0001]),
+            Transaction::write(0x72, vec![0x01, 0b1000_0000]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x72, vec![0x01, 0b0000_0000]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c)
+            .with_chip(Tca9548)
+            .with_address(0x72)
+            .with_control_register(0x01);
+        assert!(multiplexer.set_port(0, true).is_ok());
+        let (bus, mut i2c) = multiplexer.into_bus();
+        let mut port = bus.try_new_port(&mut i2c, 7).unwrap();
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        assert!(port.close_port().is_ok());
+        i2c.done();
+    }
+}
@@ -1,280 +1,4523 @@
 use crate::address_from_pins;
+#[cfg(feature = "embassy")]
+use crate::chip::Tca9548;
+use crate::chip::{all_ports, AddressPins, ChannelEncoder, Chip, Pca9546, PortArray, Switch};
+#[cfg(feature = "eh02")]
+use crate::error::Eh02Error;
+use crate::error::{ErasedPortError, PortOpError};
 use crate::prelude::MultiplexerError;
-use embedded_hal::i2c::{ErrorType, I2c, Operation, SevenBitAddress};
+use core::cell::Cell;
+use core::convert::Infallible;
+use core::marker::PhantomData;
+#[cfg(feature = "embassy")]
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+#[cfg(feature = "embassy")]
+use embassy_futures::select::{select, Either};
+#[cfg(feature = "embassy")]
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::{
+    Error, ErrorKind, ErrorType, I2c, Operation, SevenBitAddress, TenBitAddress,
+};
+#[cfg(feature = "eh02")]
+use embedded_hal_02::blocking::i2c as i2c02;
+#[cfg(feature = "async")]
+use embedded_hal_async::{delay::DelayNs as AsyncDelayNs, i2c::I2c as AsyncI2c};
 
-pub struct MultiplexerBus {
+pub struct MultiplexerBus<C: Chip = Pca9546> {
     address: u8,
+    deselect: u8,
+    control_register: Option<u8>,
+    idle_disconnect: bool,
+    select_retries: u8,
+    chip: PhantomData<C>,
 }
 
 impl MultiplexerBus {
-    pub fn new() -> Self {
-        Self { address: 0x70 }
+    pub const fn new() -> Self {
+        Self {
+            address: crate::ADDRESS_000,
+            // Encoder functions aren't const, the PCA9546 disables every port with 0
+            deselect: 0,
+            control_register: None,
+            idle_disconnect: false,
+            select_retries: 0,
+            chip: PhantomData,
+        }
     }
+}
 
-    /// Sets the address according to the enabled hardware settings
-    pub fn with_address_pins(mut self, a0: bool, a1: bool, a2: bool) -> Self {
-        self.address = address_from_pins(a0, a1, a2);
-        self
+impl<C: Chip> core::fmt::Debug for MultiplexerBus<C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MultiplexerBus")
+            .field("address", &format_args!("{:#04x}", self.address))
+            .field("channels", &C::CHANNELS)
+            .finish()
+    }
+}
+
+impl Default for MultiplexerBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Chip> MultiplexerBus<C> {
+    #[cfg(any(feature = "sync", feature = "async"))]
+    pub(crate) fn from_parts(address: u8, deselect: u8, control_register: Option<u8>) -> Self {
+        Self {
+            address,
+            deselect,
+            control_register,
+            idle_disconnect: false,
+            select_retries: 0,
+            chip: PhantomData,
+        }
+    }
+
+    /// Sets the chip model, e.g. `with_chip(Tca9548)` for an eight channel part
+    pub fn with_chip<D: Chip>(self, _chip: D) -> MultiplexerBus<D> {
+        MultiplexerBus {
+            address: self.address,
+            deselect: D::Encoder::deselect(),
+            control_register: self.control_register,
+            idle_disconnect: self.idle_disconnect,
+            select_retries: self.select_retries,
+            chip: PhantomData,
+        }
     }
 
     /// Sets the address
-    pub fn with_address(mut self, address: u8) -> Self {
+    pub const fn with_address(mut self, address: u8) -> Self {
         self.address = address;
         self
     }
 
+    /// Configured address
+    pub const fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Number of channels of the chip, set with [`with_chip`](Self::with_chip)
+    pub const fn channels(&self) -> u8 {
+        C::CHANNELS
+    }
+
+    /// Changes the address used by ports created from now on
+    pub fn set_address(&mut self, address: u8) -> Result<(), MultiplexerError<Infallible>> {
+        if address > 0x7F {
+            return Err(MultiplexerError::InvalidAddress(address));
+        }
+        self.address = address;
+        Ok(())
+    }
+
+    /// Sets the control byte written when a port is closed, some clones need a specific value
+    pub fn with_deselect_byte(mut self, deselect: u8) -> Self {
+        self.deselect = deselect;
+        self
+    }
+
+    /// Prefixes the port select write with `register`, for parts whose channel select lives
+    /// behind a register address
+    pub fn with_control_register(mut self, register: u8) -> Self {
+        self.control_register = Some(register);
+        self
+    }
+
+    /// Writes the deselect byte after every operation of the ports created from now on, also
+    /// when the downstream transfer failed. Devices with the same address on different ports
+    /// can't be reached by accident in between, at the cost of a second control write
+    pub fn with_idle_disconnect(mut self, disconnect: bool) -> Self {
+        self.idle_disconnect = disconnect;
+        self
+    }
+
+    /// Retries a failed select write of the ports created from now on up to `retries` more
+    /// times. The downstream operation is never retried
+    pub fn with_select_retries(mut self, retries: u8) -> Self {
+        self.select_retries = retries;
+        self
+    }
+
+    /// Creates a port for every channel in channel order, each with a clone of the bus handle
+    pub fn ports<I2C: Clone>(&self, i2c: I2C) -> C::Ports<BusPort<I2C>> {
+        self.ports_with(|_| i2c.clone())
+    }
+
+    /// Creates a port for every channel in channel order, with the bus handle `f` returns for
+    /// the port, e.g. a `RefCellDevice` which can't be cloned
+    pub fn ports_with<I2C>(&self, mut f: impl FnMut(u8) -> I2C) -> C::Ports<BusPort<I2C>> {
+        PortArray::from_fn(|port| self.new_port(f(port as u8), port as u8))
+    }
+
+    /// Creates a port for `PORT` that keeps it in its type, ports the chip doesn't have fail to
+    /// compile
+    ///
+    /// ```
+    /// # use i2c_multiplexer::prelude::*;
+    /// let port = MultiplexerBus::new().port::<3, _>(());
+    /// assert_eq!(port.port(), 3);
+    /// ```
+    ///
+    /// ```compile_fail
+    /// # use i2c_multiplexer::prelude::*;
+    /// let port = MultiplexerBus::new().port::<4, _>(());
+    /// ```
+    pub fn port<const PORT: u8, I2C>(&self, i2c: I2C) -> ConstPort<I2C, PORT> {
+        const { assert!(PORT < C::CHANNELS, "port out of range") };
+        ConstPort(self.new_port(i2c, PORT))
+    }
+
+    /// Creates a port enabling every port in `mask`, bit `n` is port `n`. Reads fail with
+    /// `BroadcastReadUnsupported` unless only one port is enabled. An empty mask or ports the
+    /// chip doesn't have or can't enable together fail with `UnsupportedPorts`
+    pub fn new_group<I2C>(
+        &self,
+        i2c: I2C,
+        mask: u8,
+    ) -> Result<BusPort<I2C>, MultiplexerError<I2C::Error>>
+    where
+        I2C: ErrorType,
+    {
+        let code = Self::mask_code(mask)?;
+        Ok(self.group_port(i2c, code, mask))
+    }
+
+    fn mask_code<E>(mask: u8) -> Result<u8, MultiplexerError<E>> {
+        match mask {
+            0 => None,
+            mask if mask & !all_ports::<C>() != 0 => None,
+            mask => C::Encoder::select_mask(mask),
+        }
+        .ok_or(MultiplexerError::UnsupportedPorts(mask))
+    }
+
+    /// Writes the deselect byte on `i2c` without a port, e.g. from a fault handler
+    pub fn disable_all<I2C: I2c>(&self, i2c: &mut I2C) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.write_control(i2c, self.deselect)
+    }
+
+    /// Enables the ports in `mask` on `i2c` without a port, bit `n` is port `n`. An empty mask
+    /// or ports the chip doesn't have or can't enable together fail with `UnsupportedPorts`
+    pub fn select<I2C: I2c>(
+        &self,
+        i2c: &mut I2C,
+        mask: u8,
+    ) -> Result<(), MultiplexerError<I2C::Error>> {
+        let code = Self::mask_code(mask)?;
+        self.write_control(i2c, code)
+    }
+
+    fn write_control<I2C: I2c>(
+        &self,
+        i2c: &mut I2C,
+        code: u8,
+    ) -> Result<(), MultiplexerError<I2C::Error>> {
+        match self.control_register {
+            Some(register) => i2c.write(self.address, &[register, code]),
+            None => i2c.write(self.address, &[code]),
+        }
+        .map_err(MultiplexerError::Select)
+    }
+
+    fn group_port<I2C>(&self, i2c: I2C, code: u8, mask: u8) -> BusPort<I2C> {
+        let mut port = self.new_port(i2c, 0);
+        port.port = code;
+        port.mask = mask;
+        port.write_only = mask.count_ones() > 1;
+        port
+    }
+
+    /// Creates a port, rejecting ports the chip doesn't have
+    pub fn try_new_port<I2C>(
+        &self,
+        i2c: I2C,
+        port: u8,
+    ) -> Result<BusPort<I2C>, MultiplexerError<I2C::Error>>
+    where
+        I2C: ErrorType,
+    {
+        if port >= C::CHANNELS {
+            return Err(MultiplexerError::InvalidPort(port));
+        }
+
+        Ok(self.new_port(i2c, port))
+    }
+
+    /// Creates a port and writes its select byte once to confirm the multiplexer acknowledges
+    /// its address. The port stays selected afterwards. On failure the bus is handed back with
+    /// the error
+    pub fn new_port_checked<I2C>(
+        &self,
+        i2c: I2C,
+        port: u8,
+    ) -> Result<BusPort<I2C>, (I2C, MultiplexerError<I2C::Error>)>
+    where
+        I2C: I2c,
+    {
+        if port >= C::CHANNELS {
+            return Err((i2c, MultiplexerError::InvalidPort(port)));
+        }
+        let mut port = self.new_port(i2c, port);
+        match port.open_port() {
+            Ok(()) => Ok(port),
+            Err(e) => Err((port.bus, e)),
+        }
+    }
+
+    /// Creates a port
+    ///
+    /// # Panics
+    ///
+    /// If the chip doesn't have `port`, use [`try_new_port`](Self::try_new_port) for ports only
+    /// known at runtime
     pub fn new_port<I2C>(&self, i2c: I2C, port: u8) -> BusPort<I2C> {
-        let id = match port {
-            0 => 0b000_0001,
-            1 => 0b000_0010,
-            2 => 0b000_0100,
-            _ => 0b000_1000,
-        };
+        if port >= C::CHANNELS {
+            panic!("port {} out of range", port);
+        }
+        let id = C::Encoder::select(port);
+        let highest = C::Encoder::select(C::CHANNELS - 1);
 
         BusPort {
             bus: i2c,
             address: self.address,
             port: id,
+            mask: 1 << port,
+            deselect: self.deselect,
+            control_register: self.control_register,
+            settle_ns: 0,
+            idle_disconnect: self.idle_disconnect,
+            pre_select: true,
+            write_only: false,
+            select_retries: self.select_retries,
+            backoff_us: 0,
+            retries: 0,
+            verify_select: false,
+            verify_before: false,
+            external_changes: 0,
+            verify_mask: u8::MAX >> highest.leading_zeros(),
+            allowed_addresses: 0,
+            channels: C::CHANNELS,
+            select: C::Encoder::select,
+            delay: NoDelay,
+        }
+    }
+
+    /// Adds a cache of the last control byte written, shared by the ports created with
+    /// [`CachedBus::new_cached_port`]
+    pub fn with_cache(self) -> CachedBus<C> {
+        CachedBus {
+            bus: self,
+            selected: Cell::new(None),
         }
     }
 }
 
-pub struct BusPort<I2C> {
-    bus: I2C,
-    address: u8,
-    port: u8,
+/// [`MultiplexerBus`] with a cache of the last control byte written by its cached ports, created
+/// by [`MultiplexerBus::with_cache`]. Unlike the bus it isn't `Sync`
+pub struct CachedBus<C: Chip = Pca9546> {
+    bus: MultiplexerBus<C>,
+    /// Last control byte written, `None` if unknown
+    selected: Cell<Option<u8>>,
 }
 
-impl<I2C> BusPort<I2C>
-where
-    I2C: I2c,
-{
-    fn open_port(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
-        match self.bus.write(self.address, &[self.port]) {
-            Ok(res) => Ok(res),
-            Err(_) => Err(MultiplexerError::PortError),
+impl<C: Chip> CachedBus<C> {
+    /// Bus configuration, e.g. to create ports which don't use the cache
+    pub fn bus(&self) -> &MultiplexerBus<C> {
+        &self.bus
+    }
+
+    /// Returns the bus configuration without the cache
+    pub fn into_inner(self) -> MultiplexerBus<C> {
+        self.bus
+    }
+
+    /// Creates a port sharing the last written control byte with the other cached ports of this
+    /// bus, the select write is skipped while it already matches
+    pub fn new_cached_port<I2C>(&self, i2c: I2C, port: u8) -> CachedPort<'_, I2C> {
+        CachedPort {
+            port: self.bus.new_port(i2c, port),
+            selected: &self.selected,
+        }
+    }
+
+    /// Forgets the last written control byte, e.g. after the multiplexer was reset
+    pub fn invalidate(&self) {
+        self.selected.set(None);
+    }
+
+    /// Writes the deselect byte, see [`MultiplexerBus::disable_all`]. Cached ports select again
+    /// on their next operation
+    pub fn disable_all<I2C: I2c>(&self, i2c: &mut I2C) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.record(self.bus.deselect, self.bus.disable_all(i2c))
+    }
+
+    /// Enables the ports in `mask`, see [`MultiplexerBus::select`]. Cached ports see the write
+    pub fn select<I2C: I2c>(
+        &self,
+        i2c: &mut I2C,
+        mask: u8,
+    ) -> Result<(), MultiplexerError<I2C::Error>> {
+        let code = MultiplexerBus::<C>::mask_code(mask)?;
+        self.record(code, self.bus.write_control(i2c, code))
+    }
+
+    fn record<E>(&self, code: u8, result: Result<(), E>) -> Result<(), E> {
+        self.selected.set(result.is_ok().then_some(code));
+        result
+    }
+}
+
+impl<C: Chip> core::fmt::Debug for CachedBus<C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CachedBus")
+            .field("bus", &self.bus)
+            .field("selected", &self.selected.get())
+            .finish()
+    }
+}
+
+impl<C: Switch> MultiplexerBus<C> {
+    /// Creates a port enabling every channel, so one write reaches the devices behind all of
+    /// them. Reads fail with `BroadcastReadUnsupported` since every device would drive the bus
+    pub fn broadcast_port<I2C>(&self, i2c: I2C) -> BusPort<I2C> {
+        let code =
+            C::Encoder::select_mask(all_ports::<C>()).expect("switches can enable every port");
+        self.group_port(i2c, code, all_ports::<C>())
+    }
+}
+
+impl<C: AddressPins> MultiplexerBus<C> {
+    /// Sets the address according to the enabled hardware settings
+    pub const fn with_address_pins(mut self, a0: bool, a1: bool, a2: bool) -> Self {
+        self.address = address_from_pins(a0, a1, a2);
+        self
+    }
+}
+
+#[cfg(feature = "embassy")]
+impl<C: Chip> MultiplexerBus<C> {
+    /// Creates an async port for every channel, all sharing the mutex guarded bus
+    pub fn split_async<'a, M, I2C>(
+        &self,
+        bus: &'a Mutex<M, I2C>,
+    ) -> C::Ports<AsyncBusPort<'a, M, I2C>>
+    where
+        M: RawMutex,
+        I2C: AsyncI2c,
+    {
+        PortArray::from_fn(|port| self.new_port(I2cDevice::new(bus), port as u8))
+    }
+
+    /// Creates a port holding the bus mutex across the select write and the operation
+    pub fn new_atomic_port<'a, M, I2C>(
+        &self,
+        bus: &'a Mutex<M, I2C>,
+        port: u8,
+    ) -> AtomicBusPort<'a, M, I2C>
+    where
+        M: RawMutex,
+        I2C: AsyncI2c,
+    {
+        AtomicBusPort {
+            bus,
+            port: self.new_port((), port),
+        }
+    }
+
+    /// Creates an atomic port for every channel, see [`new_atomic_port`](Self::new_atomic_port)
+    pub fn split_atomic<'a, M, I2C>(
+        &self,
+        bus: &'a Mutex<M, I2C>,
+    ) -> C::Ports<AtomicBusPort<'a, M, I2C>>
+    where
+        M: RawMutex,
+        I2C: AsyncI2c,
+    {
+        PortArray::from_fn(|port| self.new_atomic_port(bus, port as u8))
+    }
+}
+
+/// Port sharing an embassy mutex guarded bus
+#[cfg(feature = "embassy")]
+pub type AsyncBusPort<'a, M, I2C> = BusPort<I2cDevice<'a, M, I2C>>;
+
+/// Port which keeps the bus locked from the select write until the operation is done, so no
+/// other port can switch the channel in between
+#[cfg(feature = "embassy")]
+pub struct AtomicBusPort<'a, M: RawMutex, I2C> {
+    bus: &'a Mutex<M, I2C>,
+    port: BusPort<()>,
+}
+
+#[cfg(feature = "embassy")]
+impl<D> BusPort<(), D> {
+    /// Borrows the port settings for a single operation on the locked bus
+    pub(crate) fn on<'b, I2C>(&'b mut self, bus: &'b mut I2C) -> BusPort<&'b mut I2C, &'b mut D> {
+        BusPort {
+            bus,
+            address: self.address,
+            port: self.port,
+            mask: self.mask,
+            deselect: self.deselect,
+            control_register: self.control_register,
+            settle_ns: self.settle_ns,
+            idle_disconnect: self.idle_disconnect,
+            pre_select: self.pre_select,
+            write_only: self.write_only,
+            select_retries: self.select_retries,
+            backoff_us: self.backoff_us,
+            retries: self.retries,
+            verify_select: self.verify_select,
+            verify_before: self.verify_before,
+            external_changes: self.external_changes,
+            verify_mask: self.verify_mask,
+            allowed_addresses: self.allowed_addresses,
+            channels: self.channels,
+            select: self.select,
+            delay: &mut self.delay,
         }
     }
 }
 
-impl<I2C> ErrorType for BusPort<I2C>
+#[cfg(feature = "embassy")]
+impl<M, I2C> ErrorType for AtomicBusPort<'_, M, I2C>
 where
-    I2C: I2c,
+    M: RawMutex,
+    I2C: ErrorType,
 {
     type Error = MultiplexerError<I2C::Error>;
 }
 
-impl<I2C> I2c for BusPort<I2C>
+#[cfg(feature = "embassy")]
+impl<M, I2C> AsyncI2c for AtomicBusPort<'_, M, I2C>
 where
-    I2C: I2c,
+    M: RawMutex,
+    I2C: AsyncI2c,
 {
-    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
-        self.open_port()?;
-        self.bus
-            .read(address, read)
-            .map_err(|err| MultiplexerError::I2CError(err))
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        self.port.on(&mut *bus).read(address, read).await
     }
 
-    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
-        self.open_port()?;
-        self.bus
-            .write(address, write)
-            .map_err(|err| MultiplexerError::I2CError(err))
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        self.port.on(&mut *bus).write(address, write).await
     }
 
-    fn write_read(
+    async fn write_read(
         &mut self,
         address: SevenBitAddress,
         write: &[u8],
         read: &mut [u8],
     ) -> Result<(), Self::Error> {
-        self.open_port()?;
-        self.bus
+        let mut bus = self.bus.lock().await;
+        self.port
+            .on(&mut *bus)
             .write_read(address, write, read)
-            .map_err(|err| MultiplexerError::I2CError(err))
+            .await
     }
 
-    fn transaction(
+    async fn transaction(
         &mut self,
         address: SevenBitAddress,
         operations: &mut [Operation<'_>],
     ) -> Result<(), Self::Error> {
-        self.open_port()?;
-        self.bus
+        let mut bus = self.bus.lock().await;
+        self.port
+            .on(&mut *bus)
             .transaction(address, operations)
-            .map_err(|err| MultiplexerError::I2CError(err))
+            .await
     }
 }
 
-#[cfg(test)]
-mod test {
-    extern crate alloc;
-    use crate::prelude::*;
-    use alloc::vec;
-    use core::cell::RefCell;
-    use embedded_hal::i2c::I2c;
-    use embedded_hal_bus::i2c::RefCellDevice;
-    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+/// Port behind cascaded multiplexers, e.g. a PCA9545 on port 2 of a TCA9548. Every operation
+/// selects each hop root first and runs the transfer under one bus lock
+#[cfg(feature = "embassy")]
+pub struct PathPort<'a, M: RawMutex, I2C, const N: usize> {
+    bus: &'a Mutex<M, I2C>,
+    hops: [BusPort<()>; N],
+    deselect_parents: bool,
+}
 
-    #[test]
-    fn multi_port_write() {
-        let multiplexer_addr = 0x01;
-        let component_addr = 0x02;
+/// Creates a [`PathPort`] for a path of `(address, port)` pairs, root first. Every hop is treated
+/// as a switch, build the hops with [`PathPort::new`] for other chips
+#[cfg(feature = "embassy")]
+pub fn select_path<M, I2C, const N: usize>(
+    bus: &Mutex<M, I2C>,
+    path: [(u8, u8); N],
+) -> PathPort<'_, M, I2C, N>
+where
+    M: RawMutex,
+{
+    PathPort::new(
+        bus,
+        path.map(|(address, port)| {
+            MultiplexerBus::new()
+                .with_chip(Tca9548)
+                .with_address(address)
+                .new_port((), port)
+        }),
+    )
+}
 
-        // Use port 1, 3, 2, 4 in that order
-        let ports = vec![
-            (0, 0b000_0001),
-            (2, 0b000_0100),
-            (1, 0b000_0010),
-            (3, 0b000_1000),
-        ];
+#[cfg(feature = "embassy")]
+impl<'a, M: RawMutex, I2C, const N: usize> PathPort<'a, M, I2C, N> {
+    /// Creates a port from the hops, root first, created with `new_port((), port)`
+    pub fn new(bus: &'a Mutex<M, I2C>, hops: [BusPort<()>; N]) -> Self {
+        Self {
+            bus,
+            hops,
+            deselect_parents: false,
+        }
+    }
 
-        let expectations = [
-            Transaction::write(multiplexer_addr, vec![ports[0].1]),
-            Transaction::write(component_addr, vec![0x05, 0x43]),
-            Transaction::write(multiplexer_addr, vec![ports[1].1]),
-            Transaction::write(component_addr, vec![0x55]),
-            Transaction::write(multiplexer_addr, vec![ports[2].1]),
-            Transaction::write(component_addr, vec![0x07, 0x39, 0x87]),
-            Transaction::write(multiplexer_addr, vec![ports[3].1]),
-            Transaction::write(component_addr, vec![0x45, 0x48]),
-        ];
+    /// Deselects every hop but the leaf after each operation, deepest first, so the upstream
+    /// multiplexers don't keep the path connected
+    pub fn with_parent_deselect(mut self, deselect: bool) -> Self {
+        self.deselect_parents = deselect;
+        self
+    }
+}
 
-        let i2c = RefCell::new(Mock::new(&expectations));
-        let multiplexer = MultiplexerBus::new().with_address(multiplexer_addr);
+#[cfg(feature = "embassy")]
+impl<M, I2C, const N: usize> ErrorType for PathPort<'_, M, I2C, N>
+where
+    M: RawMutex,
+    I2C: ErrorType,
+{
+    type Error = MultiplexerError<I2C::Error>;
+}
 
-        {
-            let mut multiplexed_i2c_a = multiplexer.new_port(RefCellDevice::new(&i2c), ports[0].0);
-            let mut multiplexed_i2c_b = multiplexer.new_port(RefCellDevice::new(&i2c), ports[1].0);
-            let mut multiplexed_i2c_c = multiplexer.new_port(RefCellDevice::new(&i2c), ports[2].0);
-            let mut multiplexed_i2c_d = multiplexer.new_port(RefCellDevice::new(&i2c), ports[3].0);
+#[cfg(feature = "embassy")]
+async fn open_parents<I2C: AsyncI2c>(
+    parents: &mut [BusPort<()>],
+    bus: &mut I2C,
+) -> Result<(), MultiplexerError<I2C::Error>> {
+    for hop in parents.iter_mut() {
+        hop.on(&mut *bus).open_port_async().await?;
+    }
+    Ok(())
+}
 
-            assert!(multiplexed_i2c_a
-                .write(component_addr, &[0x05, 0x43])
-                .is_ok());
-            assert!(multiplexed_i2c_b.write(component_addr, &[0x55]).is_ok());
-            assert!(multiplexed_i2c_c
-                .write(component_addr, &[0x07, 0x39, 0x87])
-                .is_ok());
-            assert!(multiplexed_i2c_d
-                .write(component_addr, &[0x45, 0x48])
-                .is_ok());
-        }
+#[cfg(feature = "embassy")]
+async fn close_parents<I2C: AsyncI2c>(
+    parents: &mut [BusPort<()>],
+    bus: &mut I2C,
+    result: Result<(), MultiplexerError<I2C::Error>>,
+) -> Result<(), MultiplexerError<I2C::Error>> {
+    let mut closed = Ok(());
+    for hop in parents.iter_mut().rev() {
+        closed = closed.and(hop.on(&mut *bus).close_port_async().await);
+    }
+    result.and(closed)
+}
 
-        i2c.into_inner().done();
+#[cfg(feature = "embassy")]
+impl<M, I2C, const N: usize> AsyncI2c for PathPort<'_, M, I2C, N>
+where
+    M: RawMutex,
+    I2C: AsyncI2c,
+{
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        let Some((leaf, parents)) = self.hops.split_last_mut() else {
+            return bus
+                .read(address, read)
+                .await
+                .map_err(MultiplexerError::Transfer);
+        };
+        open_parents(parents, &mut *bus).await?;
+        let result = leaf.on(&mut *bus).read(address, read).await;
+        match self.deselect_parents {
+            true => close_parents(parents, &mut *bus, result).await,
+            false => result,
+        }
     }
 
-    #[test]
-    fn multi_port_read() {
-        let multiplexer_addr = 0x01;
-        let component_addr = 0x02;
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        let Some((leaf, parents)) = self.hops.split_last_mut() else {
+            return bus
+                .write(address, write)
+                .await
+                .map_err(MultiplexerError::Transfer);
+        };
+        open_parents(parents, &mut *bus).await?;
+        let result = leaf.on(&mut *bus).write(address, write).await;
+        match self.deselect_parents {
+            true => close_parents(parents, &mut *bus, result).await,
+            false => result,
+        }
+    }
 
-        // Use port 1, 3, 2, 4 in that order
-        let ports = vec![
-            (0, 0b000_0001),
-            (2, 0b000_0100),
-            (1, 0b000_0010),
-            (3, 0b000_1000),
-        ];
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        let Some((leaf, parents)) = self.hops.split_last_mut() else {
+            return bus
+                .write_read(address, write, read)
+                .await
+                .map_err(MultiplexerError::Transfer);
+        };
+        open_parents(parents, &mut *bus).await?;
+        let result = leaf.on(&mut *bus).write_read(address, write, read).await;
+        match self.deselect_parents {
+            true => close_parents(parents, &mut *bus, result).await,
+            false => result,
+        }
+    }
 
-        let expectations = [
-            Transaction::write(multiplexer_addr, vec![ports[0].1]),
-            Transaction::read(component_addr, vec![0x05, 0x43]),
-            Transaction::write(multiplexer_addr, vec![ports[1].1]),
-            Transaction::read(component_addr, vec![0x55]),
-            Transaction::write(multiplexer_addr, vec![ports[2].1]),
-            Transaction::read(component_addr, vec![0x07, 0x39, 0x87]),
-            Transaction::write(multiplexer_addr, vec![ports[3].1]),
-            Transaction::read(component_addr, vec![0x45, 0x48]),
-        ];
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+        let mut bus = self.bus.lock().await;
+        let Some((leaf, parents)) = self.hops.split_last_mut() else {
+            return bus
+                .transaction(address, operations)
+                .await
+                .map_err(MultiplexerError::Transfer);
+        };
+        open_parents(parents, &mut *bus).await?;
+        let result = leaf.on(&mut *bus).transaction(address, operations).await;
+        match self.deselect_parents {
+            true => close_parents(parents, &mut *bus, result).await,
+            false => result,
+        }
+    }
+}
 
-        let i2c = RefCell::new(Mock::new(&expectations));
-        let multiplexer = MultiplexerBus::new().with_address(multiplexer_addr);
+/// Async port which fails with [`MultiplexerError::Timeout`] when an operation takes longer
+/// than the timeout, e.g. a downstream device stretching the clock forever. The pending
+/// operation is dropped and the next one selects the channel again
+#[cfg(feature = "embassy")]
+pub struct TimeoutPort<P, T> {
+    port: P,
+    timer: T,
+    timeout_us: u32,
+}
 
-        {
-            let mut multiplexed_i2c_a = multiplexer.new_port(RefCellDevice::new(&i2c), ports[0].0);
-            let mut multiplexed_i2c_b = multiplexer.new_port(RefCellDevice::new(&i2c), ports[1].0);
-            let mut multiplexed_i2c_c = multiplexer.new_port(RefCellDevice::new(&i2c), ports[2].0);
-            let mut multiplexed_i2c_d = multiplexer.new_port(RefCellDevice::new(&i2c), ports[3].0);
+#[cfg(feature = "embassy")]
+impl<P, T> TimeoutPort<P, T> {
+    /// Wraps `port`, using `timer` to wait out `timeout_us` for every operation
+    pub fn new(port: P, timer: T, timeout_us: u32) -> Self {
+        Self {
+            port,
+            timer,
+            timeout_us,
+        }
+    }
 
-            let mut ma = [0; 2];
-            assert!(multiplexed_i2c_a.read(component_addr, &mut ma).is_ok());
-            assert_eq!(ma, [0x05, 0x43]);
+    /// Returns the wrapped port
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+}
 
-            let mut mb = [0; 1];
-            assert!(multiplexed_i2c_b.read(component_addr, &mut mb).is_ok());
+#[cfg(feature = "embassy")]
+async fn with_timeout<E, T>(
+    timer: &mut T,
+    timeout_us: u32,
+    operation: impl core::future::Future<Output = Result<(), MultiplexerError<E>>>,
+) -> Result<(), MultiplexerError<E>>
+where
+    E: embedded_hal::i2c::Error,
+    T: AsyncDelayNs,
+{
+    match select(operation, timer.delay_us(timeout_us)).await {
+        Either::First(result) => result,
+        Either::Second(()) => Err(MultiplexerError::Timeout),
+    }
+}
+
+#[cfg(feature = "embassy")]
+impl<P, T, E> ErrorType for TimeoutPort<P, T>
+where
+    P: ErrorType<Error = MultiplexerError<E>>,
+    E: embedded_hal::i2c::Error,
+{
+    type Error = MultiplexerError<E>;
+}
+
+#[cfg(feature = "embassy")]
+impl<P, T, E> AsyncI2c for TimeoutPort<P, T>
+where
+    P: AsyncI2c<Error = MultiplexerError<E>>,
+    T: AsyncDelayNs,
+    E: embedded_hal::i2c::Error,
+{
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let operation = self.port.read(address, read);
+        with_timeout(&mut self.timer, self.timeout_us, operation).await
+    }
+
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let operation = self.port.write(address, write);
+        with_timeout(&mut self.timer, self.timeout_us, operation).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let operation = self.port.write_read(address, write, read);
+        with_timeout(&mut self.timer, self.timeout_us, operation).await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let operation = self.port.transaction(address, operations);
+        with_timeout(&mut self.timer, self.timeout_us, operation).await
+    }
+}
+
+/// Port of a [`MultiplexerBus`], every operation writes the select byte first. Empty
+/// transactions are a no-op without bus traffic, zero-length reads and writes still select the
+/// port and reach the device, so they work as probes
+pub struct BusPort<I2C, D = NoDelay> {
+    bus: I2C,
+    address: u8,
+    /// Control byte selecting the port
+    port: u8,
+    /// Enabled ports, bit `n` is port `n`
+    mask: u8,
+    deselect: u8,
+    control_register: Option<u8>,
+    settle_ns: u32,
+    idle_disconnect: bool,
+    pre_select: bool,
+    write_only: bool,
+    select_retries: u8,
+    backoff_us: u32,
+    retries: u32,
+    verify_select: bool,
+    verify_before: bool,
+    /// Operations which found another control byte when verifying before them
+    external_changes: u32,
+    verify_mask: u8,
+    /// Allowed device addresses, bit `n` is address `n`, empty allows every address
+    allowed_addresses: u128,
+    channels: u8,
+    select: fn(u8) -> u8,
+    delay: D,
+}
+
+impl<I2C, D> core::fmt::Debug for BusPort<I2C, D> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BusPort")
+            .field("port", &self.port())
+            .field("mask", &format_args!("{:#010b}", self.mask))
+            .field(
+                "multiplexer_address",
+                &format_args!("{:#04x}", self.address),
+            )
+            .finish()
+    }
+}
+
+/// Default [`BusPort`] delay, never waits
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+#[cfg(feature = "async")]
+impl AsyncDelayNs for NoDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
+
+impl<I2C, D> BusPort<I2C, D> {
+    /// Returns the bus
+    pub fn into_inner(self) -> I2C {
+        self.bus
+    }
+
+    /// Port, the lowest one for a group
+    pub fn port(&self) -> u8 {
+        self.mask.trailing_zeros() as u8
+    }
+
+    /// Enabled ports, bit `n` is port `n`
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
+
+    /// Address of the multiplexer
+    pub fn multiplexer_address(&self) -> u8 {
+        self.address
+    }
+
+    /// Switches the port to another channel of the same chip, rejecting ports it doesn't have
+    pub fn set_port(&mut self, port: u8) -> Result<(), MultiplexerError<Infallible>> {
+        if port >= self.channels {
+            return Err(MultiplexerError::InvalidPort(port));
+        }
+        self.port = (self.select)(port);
+        self.mask = 1 << port;
+        self.write_only = false;
+        Ok(())
+    }
+
+    /// Switches the port to another channel, see [`set_port`](Self::set_port)
+    pub fn with_port(mut self, port: u8) -> Result<Self, MultiplexerError<Infallible>> {
+        self.set_port(port)?;
+        Ok(self)
+    }
+
+    /// Returns the bus and the port, the lowest one for a group, e.g. to create a port for
+    /// another channel on the same bus
+    pub fn release(self) -> (I2C, u8) {
+        let port = self.port();
+        (self.bus, port)
+    }
+
+    /// Sets the delay used for the settle time and the select retry backoff
+    pub fn with_delay<E>(self, delay: E) -> BusPort<I2C, E> {
+        BusPort {
+            bus: self.bus,
+            address: self.address,
+            port: self.port,
+            mask: self.mask,
+            deselect: self.deselect,
+            control_register: self.control_register,
+            settle_ns: self.settle_ns,
+            idle_disconnect: self.idle_disconnect,
+            pre_select: self.pre_select,
+            write_only: self.write_only,
+            select_retries: self.select_retries,
+            backoff_us: self.backoff_us,
+            retries: self.retries,
+            verify_select: self.verify_select,
+            verify_before: self.verify_before,
+            external_changes: self.external_changes,
+            verify_mask: self.verify_mask,
+            allowed_addresses: self.allowed_addresses,
+            channels: self.channels,
+            select: self.select,
+            delay,
+        }
+    }
+
+    /// Waits `ns` after every select write before the downstream operation, 0 disables it.
+    /// Cached ports only wait when they write the select byte
+    pub fn with_settle_delay<E>(self, ns: u32, delay: E) -> BusPort<I2C, E> {
+        let mut port = self.with_delay(delay);
+        port.settle_ns = ns;
+        port
+    }
+
+    /// Retries a failed select write up to `retries` times, waiting `backoff_us` on the port
+    /// delay between attempts. The downstream operation is never retried. Once every attempt
+    /// failed the error is `SelectFailed` with the number of attempts
+    pub fn with_select_retries(mut self, retries: u8, backoff_us: u32) -> Self {
+        self.select_retries = retries;
+        self.backoff_us = backoff_us;
+        self
+    }
+
+    /// Reads the control register back after every select write, rewriting it once on a
+    /// mismatch. Bits the chip uses for interrupts are ignored. The embedded-hal 0.2 impls can't
+    /// read and skip it
+    pub fn with_verified_select(mut self, verify: bool) -> Self {
+        self.verify_select = verify;
+        self
+    }
+
+    /// Rejects operations on device addresses not in `addresses` with `AddressNotAllowed` before
+    /// touching the bus, an empty list allows every address
+    ///
+    /// # Panics
+    ///
+    /// If an address isn't a 7-bit address
+    pub fn with_allowed_addresses(mut self, addresses: &[u8]) -> Self {
+        self.allowed_addresses = addresses.iter().fold(0, |allowed, &address| {
+            if address > 0x7F {
+                panic!("address {:#04x} isn't a 7-bit address", address);
+            }
+            allowed | 1 << address
+        });
+        self
+    }
+
+    /// With `false` operations skip the select write, [`select`](Self::select) writes it once.
+    /// Only safe when nothing else on the bus switches the multiplexer, other ports on the same
+    /// multiplexer would leave their channel selected. Don't combine it with idle disconnect
+    pub fn with_pre_select(mut self, pre_select: bool) -> Self {
+        self.pre_select = pre_select;
+        self
+    }
+
+    /// Reads the control register before every operation and only writes the select byte when
+    /// it doesn't match, e.g. when another master switches the multiplexer. Costs a read per
+    /// operation, bits the chip uses for interrupts are ignored. The embedded-hal 0.2 impls
+    /// always write the select byte, their `Write` bus can't read the register
+    pub fn with_verify_before_operation(mut self, verify: bool) -> Self {
+        self.verify_before = verify;
+        self
+    }
+
+    /// Number of operations which found another control byte when verifying before them,
+    /// including the first one if the port wasn't selected yet
+    pub fn external_changes(&self) -> u32 {
+        self.external_changes
+    }
+
+    /// Number of select retries consumed so far
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Error of the last failed select attempt, `Select` when retries are disabled
+    fn select_failed<E>(&self, error: E) -> MultiplexerError<E> {
+        match self.select_retries {
+            0 => MultiplexerError::Select(error),
+            retries => MultiplexerError::SelectFailed {
+                attempts: u16::from(retries) + 1,
+                error,
+            },
+        }
+    }
+
+    fn allowed<E>(&self, address: u16) -> Result<(), MultiplexerError<E>> {
+        match self.allowed_addresses == 0
+            || (address <= 0x7F && self.allowed_addresses & 1 << address != 0)
+        {
+            true => Ok(()),
+            false => Err(MultiplexerError::AddressNotAllowed(address)),
+        }
+    }
+
+    fn readable<E>(&self, reads: bool) -> Result<(), MultiplexerError<E>> {
+        match self.write_only && reads {
+            true => Err(MultiplexerError::BroadcastReadUnsupported),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<I2C, D: DelayNs> BusPort<I2C, D> {
+    /// Writes the select byte with `write`, retrying and waiting as configured. Shared by the
+    /// embedded-hal 1.0 and 0.2 impls
+    fn select_with<E>(
+        &mut self,
+        mut write: impl FnMut(&mut Self) -> Result<(), E>,
+        verify: impl FnOnce(&mut Self) -> Result<(), MultiplexerError<E>>,
+    ) -> Result<(), MultiplexerError<E>> {
+        let mut attempt = 0;
+        loop {
+            match write(self) {
+                Ok(()) => break,
+                Err(e) if attempt == self.select_retries => return Err(self.select_failed(e)),
+                Err(_) => {}
+            }
+            attempt += 1;
+            self.retries = self.retries.wrapping_add(1);
+            if self.backoff_us > 0 {
+                self.delay.delay_us(self.backoff_us);
+            }
+        }
+        if self.verify_select {
+            verify(self)?;
+        }
+        if self.settle_ns > 0 {
+            self.delay.delay_ns(self.settle_ns);
+        }
+        Ok(())
+    }
+}
+
+impl<I2C, D> BusPort<I2C, D>
+where
+    I2C: I2c,
+    D: DelayNs,
+{
+    fn open_port(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        if !self.pre_select {
+            return Ok(());
+        }
+        if self.verify_before {
+            if self.read_control()? & self.verify_mask == self.port {
+                return Ok(());
+            }
+            self.external_changes = self.external_changes.wrapping_add(1);
+        }
+        self.select()
+    }
+
+    fn read_control(&mut self) -> Result<u8, MultiplexerError<I2C::Error>> {
+        let mut read = [0];
+        match self.control_register {
+            Some(register) => self.bus.write_read(self.address, &[register], &mut read),
+            None => self.bus.read(self.address, &mut read),
+        }
+        .map_err(MultiplexerError::Select)?;
+        Ok(read[0])
+    }
+
+    /// Writes the select byte, with the retries and the settle delay of the port. Ports
+    /// without pre select need it once before the first operation
+    pub fn select(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.select_with(|port| port.write_control(port.port), Self::verify_select)
+    }
+
+    fn verify_select(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        let mut actual = self.read_control()?;
+        if actual & self.verify_mask != self.port {
+            self.write_control(self.port)
+                .map_err(MultiplexerError::Select)?;
+            actual = self.read_control()?;
+        }
+        if actual & self.verify_mask != self.port {
+            return Err(MultiplexerError::SelectVerificationFailed {
+                expected: self.port,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Selects the port once and writes nothing to `address` up to `attempts` times, waiting
+    /// `interval_us` in between, until the device acknowledges, e.g. an EEPROM finishing a
+    /// write. Fails with `DeviceBusyTimeout` if it never does, other bus errors are returned
+    pub fn ack_poll(
+        &mut self,
+        address: u8,
+        attempts: u32,
+        delay: &mut impl DelayNs,
+        interval_us: u32,
+    ) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.allowed(address.into())?;
+        self.open_port()?;
+        let mut acked = Ok(false);
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                delay.delay_us(interval_us);
+            }
+            acked = match self.bus.write(address, &[]) {
+                Ok(()) => Ok(true),
+                Err(e) if matches!(e.kind(), ErrorKind::NoAcknowledge(_)) => Ok(false),
+                Err(e) => Err(e),
+            };
+            if !matches!(acked, Ok(false)) {
+                break;
+            }
+        }
+        match self.finish(acked)? {
+            true => Ok(()),
+            false => Err(MultiplexerError::DeviceBusyTimeout(address)),
+        }
+    }
+
+    /// Selects the port and writes nothing to `address`, `false` if the device didn't
+    /// acknowledge. Other bus errors are returned
+    pub fn probe(&mut self, address: u8) -> Result<bool, MultiplexerError<I2C::Error>> {
+        match I2c::write(self, address, &[]) {
+            Ok(()) => Ok(true),
+            Err(MultiplexerError::Transfer(e))
+                if matches!(e.kind(), ErrorKind::NoAcknowledge(_)) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<I2C, D> BusPort<I2C, D>
+where
+    I2C: I2c,
+{
+    /// Disconnects the port from the upstream bus
+    pub fn close_port(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.write_control(self.deselect)
+            .map_err(MultiplexerError::Select)
+    }
+
+    /// Disconnects the port and returns the bus. If the write fails the port is handed back
+    /// with the error, so the bus isn't lost
+    pub fn deselect_and_release(self) -> Result<I2C, (Self, MultiplexerError<I2C::Error>)> {
+        let mut port = self;
+        match port.close_port() {
+            Ok(()) => Ok(port.bus),
+            Err(e) => Err((port, e)),
+        }
+    }
+
+    fn write_control(&mut self, code: u8) -> Result<(), I2C::Error> {
+        match self.control_register {
+            Some(register) => self.bus.write(self.address, &[register, code]),
+            None => self.bus.write(self.address, &[code]),
+        }
+    }
+
+    /// Disconnects the port after the transfer if enabled, a transfer error is returned over
+    /// a disconnect error
+    fn finish<T>(
+        &mut self,
+        result: Result<T, I2C::Error>,
+    ) -> Result<T, MultiplexerError<I2C::Error>> {
+        let result = result.map_err(MultiplexerError::Transfer);
+        if !self.idle_disconnect {
+            return result;
+        }
+        let closed = self.close_port();
+        let value = result?;
+        closed.map(|()| value)
+    }
+}
+
+impl<I2C, D> ErrorType for BusPort<I2C, D>
+where
+    I2C: ErrorType,
+{
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<I2C, D> I2c for BusPort<I2C, D>
+where
+    I2C: I2c,
+    D: DelayNs,
+{
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.readable(true)?;
+        self.open_port()?;
+        let result = self.bus.read(address, read);
+        self.finish(result)
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.open_port()?;
+        let result = self.bus.write(address, write);
+        self.finish(result)
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.readable(true)?;
+        self.open_port()?;
+        let result = self.bus.write_read(address, write, read);
+        self.finish(result)
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+        self.allowed(address.into())?;
+        self.readable(
+            operations
+                .iter()
+                .any(|operation| matches!(operation, Operation::Read(_))),
+        )?;
+        self.open_port()?;
+        let result = self.bus.transaction(address, operations);
+        self.finish(result)
+    }
+}
+
+/// The select write stays seven bit, only the downstream device uses a ten bit address
+impl<I2C, D> I2c<TenBitAddress> for BusPort<I2C, D>
+where
+    I2C: I2c + I2c<TenBitAddress>,
+    D: DelayNs,
+{
+    fn read(&mut self, address: TenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.allowed(address)?;
+        self.readable(true)?;
+        self.open_port()?;
+        let result = I2c::<TenBitAddress>::read(&mut self.bus, address, read);
+        self.finish(result)
+    }
+
+    fn write(&mut self, address: TenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.allowed(address)?;
+        self.open_port()?;
+        let result = I2c::<TenBitAddress>::write(&mut self.bus, address, write);
+        self.finish(result)
+    }
+
+    fn write_read(
+        &mut self,
+        address: TenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.allowed(address)?;
+        self.readable(true)?;
+        self.open_port()?;
+        let result = I2c::<TenBitAddress>::write_read(&mut self.bus, address, write, read);
+        self.finish(result)
+    }
+
+    fn transaction(
+        &mut self,
+        address: TenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+        self.allowed(address)?;
+        self.readable(
+            operations
+                .iter()
+                .any(|operation| matches!(operation, Operation::Read(_))),
+        )?;
+        self.open_port()?;
+        let result = I2c::<TenBitAddress>::transaction(&mut self.bus, address, operations);
+        self.finish(result)
+    }
+}
+
+/// Port created by [`MultiplexerBus::port`], with the port in its type so drivers can require a
+/// specific one
+pub struct ConstPort<I2C, const PORT: u8, D = NoDelay>(BusPort<I2C, D>);
+
+impl<I2C, const PORT: u8, D> ConstPort<I2C, PORT, D> {
+    /// Port, same as `PORT`
+    pub fn port(&self) -> u8 {
+        PORT
+    }
+
+    /// Returns the runtime port
+    pub fn into_port(self) -> BusPort<I2C, D> {
+        self.0
+    }
+
+    /// The runtime port, e.g. for its counters
+    pub fn as_port(&self) -> &BusPort<I2C, D> {
+        &self.0
+    }
+
+    /// See [`BusPort::with_delay`]
+    pub fn with_delay<E>(self, delay: E) -> ConstPort<I2C, PORT, E> {
+        ConstPort(self.0.with_delay(delay))
+    }
+
+    /// See [`BusPort::with_settle_delay`]
+    pub fn with_settle_delay<E>(self, ns: u32, delay: E) -> ConstPort<I2C, PORT, E> {
+        ConstPort(self.0.with_settle_delay(ns, delay))
+    }
+
+    /// See [`BusPort::with_select_retries`]
+    pub fn with_select_retries(self, retries: u8, backoff_us: u32) -> Self {
+        Self(self.0.with_select_retries(retries, backoff_us))
+    }
+
+    /// See [`BusPort::with_verified_select`]
+    pub fn with_verified_select(self, verify: bool) -> Self {
+        Self(self.0.with_verified_select(verify))
+    }
+
+    /// See [`BusPort::with_verify_before_operation`]
+    pub fn with_verify_before_operation(self, verify: bool) -> Self {
+        Self(self.0.with_verify_before_operation(verify))
+    }
+
+    /// See [`BusPort::with_pre_select`]
+    pub fn with_pre_select(self, pre_select: bool) -> Self {
+        Self(self.0.with_pre_select(pre_select))
+    }
+
+    /// See [`BusPort::with_allowed_addresses`]
+    pub fn with_allowed_addresses(self, addresses: &[u8]) -> Self {
+        Self(self.0.with_allowed_addresses(addresses))
+    }
+}
+
+impl<I2C: I2c, const PORT: u8, D: DelayNs> ConstPort<I2C, PORT, D> {
+    /// See [`BusPort::select`]
+    pub fn select(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.0.select()
+    }
+}
+
+impl<I2C, const PORT: u8, D> ErrorType for ConstPort<I2C, PORT, D>
+where
+    I2C: ErrorType,
+{
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<I2C, const PORT: u8, D> I2c for ConstPort<I2C, PORT, D>
+where
+    I2C: I2c,
+    D: DelayNs,
+{
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(address, read)
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(address, write)
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.0.write_read(address, write, read)
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.0.transaction(address, operations)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C, const PORT: u8, D> AsyncI2c for ConstPort<I2C, PORT, D>
+where
+    I2C: AsyncI2c,
+    D: AsyncDelayNs,
+{
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        AsyncI2c::read(&mut self.0, address, read).await
+    }
+
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        AsyncI2c::write(&mut self.0, address, write).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        AsyncI2c::write_read(&mut self.0, address, write, read).await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        AsyncI2c::transaction(&mut self.0, address, operations).await
+    }
+}
+
+impl<I2C, D> BusPort<I2C, D>
+where
+    I2C: I2c,
+{
+    /// Writes the deselect byte when the returned port is dropped. Errors can't be returned
+    /// from drop, they are ignored unless a handler is set with
+    /// [`DeselectOnDrop::with_drop_error_handler`]
+    pub fn deselect_on_drop(self) -> DeselectOnDrop<I2C, D> {
+        DeselectOnDrop {
+            port: self,
+            on_error: None,
+        }
+    }
+}
+
+/// Port created by [`BusPort::deselect_on_drop`], blocking only since drop can't wait for an
+/// async bus
+pub struct DeselectOnDrop<I2C: I2c, D = NoDelay> {
+    port: BusPort<I2C, D>,
+    on_error: Option<fn(MultiplexerError<I2C::Error>)>,
+}
+
+impl<I2C: I2c, D> DeselectOnDrop<I2C, D> {
+    /// Calls `handler` with the error if the deselect write fails on drop
+    pub fn with_drop_error_handler(mut self, handler: fn(MultiplexerError<I2C::Error>)) -> Self {
+        self.on_error = Some(handler);
+        self
+    }
+}
+
+impl<I2C: I2c, D> Drop for DeselectOnDrop<I2C, D> {
+    fn drop(&mut self) {
+        if let (Err(e), Some(handler)) = (self.port.close_port(), self.on_error) {
+            handler(e);
+        }
+    }
+}
+
+impl<I2C: I2c, D> ErrorType for DeselectOnDrop<I2C, D> {
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<I2C: I2c, D: DelayNs> I2c for DeselectOnDrop<I2C, D> {
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.port.read(address, read)
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.port.write(address, write)
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.port.write_read(address, write, read)
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.port.transaction(address, operations)
+    }
+}
+
+/// Port calling an observer after every operation, created by [`BusPort::with_observer`]
+pub struct TracingBusPort<I2C, D, F> {
+    port: BusPort<I2C, D>,
+    observer: F,
+}
+
+/// Operation seen by the observer of a [`TracingBusPort`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BusEvent {
+    pub port: u8,
+    /// Address of the downstream device
+    pub address: u8,
+    pub operation: BusOperation,
+    /// Bytes written and read
+    pub len: usize,
+    pub outcome: BusOutcome,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BusOperation {
+    Read,
+    Write,
+    WriteRead,
+    Transaction,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BusOutcome {
+    Ok,
+    /// Writing the select or deselect byte failed
+    Select(ErrorKind),
+    /// The downstream transfer failed
+    Transfer(ErrorKind),
+    /// Failed before touching the bus, e.g. with `AddressNotAllowed`
+    Rejected,
+}
+
+impl<E: Error> From<&Result<(), MultiplexerError<E>>> for BusOutcome {
+    fn from(result: &Result<(), MultiplexerError<E>>) -> Self {
+        match result {
+            Ok(()) => Self::Ok,
+            Err(MultiplexerError::Select(e) | MultiplexerError::SelectFailed { error: e, .. }) => {
+                Self::Select(e.kind())
+            }
+            Err(MultiplexerError::Transfer(e)) => Self::Transfer(e.kind()),
+            Err(_) => Self::Rejected,
+        }
+    }
+}
+
+impl<I2C, D> BusPort<I2C, D> {
+    /// Calls `observer` after every operation with the port, device address, operation, length
+    /// and outcome, including a failed select write
+    pub fn with_observer<F>(self, observer: F) -> TracingBusPort<I2C, D, F>
+    where
+        F: FnMut(BusEvent),
+    {
+        TracingBusPort {
+            port: self,
+            observer,
+        }
+    }
+}
+
+impl<I2C, D, F> TracingBusPort<I2C, D, F>
+where
+    F: FnMut(BusEvent),
+{
+    /// Returns the port without the observer
+    pub fn into_inner(self) -> BusPort<I2C, D> {
+        self.port
+    }
+
+    fn trace<E: Error>(
+        &mut self,
+        address: u8,
+        operation: BusOperation,
+        len: usize,
+        result: Result<(), MultiplexerError<E>>,
+    ) -> Result<(), MultiplexerError<E>> {
+        (self.observer)(BusEvent {
+            port: self.port.port(),
+            address,
+            operation,
+            len,
+            outcome: (&result).into(),
+        });
+        result
+    }
+}
+
+fn operations_len(operations: &[Operation<'_>]) -> usize {
+    operations
+        .iter()
+        .map(|operation| match operation {
+            Operation::Read(read) => read.len(),
+            Operation::Write(write) => write.len(),
+        })
+        .sum()
+}
+
+impl<I2C: ErrorType, D, F> ErrorType for TracingBusPort<I2C, D, F> {
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<I2C, D, F> I2c for TracingBusPort<I2C, D, F>
+where
+    I2C: I2c,
+    D: DelayNs,
+    F: FnMut(BusEvent),
+{
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.port.read(address, read);
+        self.trace(address, BusOperation::Read, read.len(), result)
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let result = self.port.write(address, write);
+        self.trace(address, BusOperation::Write, write.len(), result)
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.write_read(address, write, read);
+        let len = write.len() + read.len();
+        self.trace(address, BusOperation::WriteRead, len, result)
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.transaction(address, operations);
+        let len = operations_len(operations);
+        self.trace(address, BusOperation::Transaction, len, result)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C, D, F> AsyncI2c for TracingBusPort<I2C, D, F>
+where
+    I2C: AsyncI2c,
+    D: AsyncDelayNs,
+    F: FnMut(BusEvent),
+{
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.port.read(address, read).await;
+        self.trace(address, BusOperation::Read, read.len(), result)
+    }
+
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let result = self.port.write(address, write).await;
+        self.trace(address, BusOperation::Write, write.len(), result)
+    }
+
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.write_read(address, write, read).await;
+        let len = write.len() + read.len();
+        self.trace(address, BusOperation::WriteRead, len, result)
+    }
+
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.transaction(address, operations).await;
+        let len = operations_len(operations);
+        self.trace(address, BusOperation::Transaction, len, result)
+    }
+}
+
+/// Port whose errors name the multiplexer address, the port and the failed step, created by
+/// [`BusPort::with_error_context`]
+pub struct ContextBusPort<I2C, D = NoDelay> {
+    port: BusPort<I2C, D>,
+}
+
+impl<I2C, D> BusPort<I2C, D> {
+    /// Wraps the port so its errors are a [`PortOpError`] naming the multiplexer address, the
+    /// port and whether the select write or the transfer failed
+    pub fn with_error_context(self) -> ContextBusPort<I2C, D> {
+        ContextBusPort { port: self }
+    }
+}
+
+impl<I2C, D> ContextBusPort<I2C, D> {
+    /// Returns the port with plain errors
+    pub fn into_inner(self) -> BusPort<I2C, D> {
+        self.port
+    }
+
+    fn context<T, E>(&self, result: Result<T, MultiplexerError<E>>) -> Result<T, PortOpError<E>> {
+        result.map_err(|e| PortOpError::new(self.port.address, self.port.port(), e))
+    }
+}
+
+impl<I2C: ErrorType, D> ErrorType for ContextBusPort<I2C, D> {
+    type Error = PortOpError<I2C::Error>;
+}
+
+impl<I2C, D> I2c for ContextBusPort<I2C, D>
+where
+    I2C: I2c,
+    D: DelayNs,
+{
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.port.read(address, read);
+        self.context(result)
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let result = self.port.write(address, write);
+        self.context(result)
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.write_read(address, write, read);
+        self.context(result)
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.transaction(address, operations);
+        self.context(result)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C, D> AsyncI2c for ContextBusPort<I2C, D>
+where
+    I2C: AsyncI2c,
+    D: AsyncDelayNs,
+{
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.port.read(address, read).await;
+        self.context(result)
+    }
+
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let result = self.port.write(address, write).await;
+        self.context(result)
+    }
+
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.write_read(address, write, read).await;
+        self.context(result)
+    }
+
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.transaction(address, operations).await;
+        self.context(result)
+    }
+}
+
+/// Port whose error is an [`ErasedPortError`] independent of the bus type, so ports over
+/// different buses fit in one `dyn I2c` collection. Created by [`BusPort::into_erased`]
+pub struct ErasedBusPort<I2C, D = NoDelay> {
+    port: BusPort<I2C, D>,
+}
+
+impl<I2C, D> BusPort<I2C, D> {
+    /// Wraps the port so its errors only keep the error kind and the context of a
+    /// [`PortOpError`]
+    pub fn into_erased(self) -> ErasedBusPort<I2C, D> {
+        ErasedBusPort { port: self }
+    }
+}
+
+impl<I2C, D> ErasedBusPort<I2C, D> {
+    /// Returns the port with plain errors
+    pub fn into_inner(self) -> BusPort<I2C, D> {
+        self.port
+    }
+
+    fn erase<E: Error>(
+        &self,
+        result: Result<(), MultiplexerError<E>>,
+    ) -> Result<(), ErasedPortError> {
+        result.map_err(|e| PortOpError::new(self.port.address, self.port.port(), e).into())
+    }
+}
+
+impl<I2C, D> ErrorType for ErasedBusPort<I2C, D> {
+    type Error = ErasedPortError;
+}
+
+impl<I2C, D> I2c for ErasedBusPort<I2C, D>
+where
+    I2C: I2c,
+    D: DelayNs,
+{
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.port.read(address, read);
+        self.erase(result)
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let result = self.port.write(address, write);
+        self.erase(result)
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.write_read(address, write, read);
+        self.erase(result)
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = self.port.transaction(address, operations);
+        self.erase(result)
+    }
+}
+
+/// Port created by [`CachedBus::new_cached_port`]. Ports of the same bus share the last
+/// control byte they wrote, any failed select or transfer forgets it so the next operation
+/// selects again. Other writes to the multiplexer aren't seen by the cache
+pub struct CachedPort<'a, I2C, D = NoDelay> {
+    port: BusPort<I2C, D>,
+    selected: &'a Cell<Option<u8>>,
+}
+
+impl<'a, I2C, D> CachedPort<'a, I2C, D> {
+    /// Waits `ns` after every select write, see [`BusPort::with_settle_delay`]
+    pub fn with_settle_delay<E>(self, ns: u32, delay: E) -> CachedPort<'a, I2C, E> {
+        CachedPort {
+            port: self.port.with_settle_delay(ns, delay),
+            selected: self.selected,
+        }
+    }
+
+    /// Returns the bus
+    pub fn into_inner(self) -> I2C {
+        self.port.into_inner()
+    }
+
+    /// Forgets the selected port of every cached port of the bus, e.g. after the multiplexer was
+    /// reset
+    pub fn invalidate(&self) {
+        self.selected.set(None);
+    }
+
+    /// Switches the port to another channel, see [`BusPort::set_port`]. The next operation
+    /// selects it
+    pub fn set_port(&mut self, port: u8) -> Result<(), MultiplexerError<Infallible>> {
+        self.port.set_port(port)?;
+        self.invalidate();
+        Ok(())
+    }
+}
+
+impl<I2C, D> CachedPort<'_, I2C, D>
+where
+    I2C: I2c,
+    D: DelayNs,
+{
+    fn open_port(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        if self.selected.get() != Some(self.port.port) {
+            self.invalidate();
+            self.port.open_port()?;
+            self.selected.set(Some(self.port.port));
+        }
+        Ok(())
+    }
+
+    /// Disconnects the port from the upstream bus
+    pub fn close_port(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.invalidate();
+        self.port.close_port()?;
+        self.selected.set(Some(self.port.deselect));
+        Ok(())
+    }
+
+    fn finish<T>(
+        &mut self,
+        result: Result<T, I2C::Error>,
+    ) -> Result<T, MultiplexerError<I2C::Error>> {
+        if result.is_err() {
+            self.invalidate();
+        }
+        let result = result.map_err(MultiplexerError::Transfer);
+        if !self.port.idle_disconnect {
+            return result;
+        }
+        let closed = self.close_port();
+        let value = result?;
+        closed.map(|()| value)
+    }
+}
+
+impl<I2C, D> ErrorType for CachedPort<'_, I2C, D>
+where
+    I2C: ErrorType,
+{
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<I2C, D> I2c for CachedPort<'_, I2C, D>
+where
+    I2C: I2c,
+    D: DelayNs,
+{
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.port.allowed(address.into())?;
+        self.open_port()?;
+        let result = self.port.bus.read(address, read);
+        self.finish(result)
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.port.allowed(address.into())?;
+        self.open_port()?;
+        let result = self.port.bus.write(address, write);
+        self.finish(result)
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.port.allowed(address.into())?;
+        self.open_port()?;
+        let result = self.port.bus.write_read(address, write, read);
+        self.finish(result)
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+        self.port.allowed(address.into())?;
+        self.open_port()?;
+        let result = self.port.bus.transaction(address, operations);
+        self.finish(result)
+    }
+}
+
+#[cfg(feature = "eh02")]
+impl<I2C, D, E> BusPort<I2C, D>
+where
+    I2C: i2c02::Write<Error = E>,
+    D: DelayNs,
+    E: core::fmt::Debug,
+{
+    fn open_port_eh02(&mut self) -> Result<(), MultiplexerError<Eh02Error<E>>> {
+        match self.pre_select {
+            // A 0.2 `Write` bus can't read the control register back
+            true => self.select_with(|port| port.write_raw_eh02(port.port), |_| Ok(())),
+            false => Ok(()),
+        }
+    }
+
+    fn write_control_eh02(&mut self, code: u8) -> Result<(), MultiplexerError<Eh02Error<E>>> {
+        self.write_raw_eh02(code).map_err(MultiplexerError::Select)
+    }
+
+    fn write_raw_eh02(&mut self, code: u8) -> Result<(), Eh02Error<E>> {
+        match self.control_register {
+            Some(register) => self.bus.write(self.address, &[register, code]),
+            None => self.bus.write(self.address, &[code]),
+        }
+        .map_err(Eh02Error)
+    }
+
+    fn finish_eh02<T>(
+        &mut self,
+        result: Result<T, E>,
+    ) -> Result<T, MultiplexerError<Eh02Error<E>>> {
+        let result = result.map_err(|e| MultiplexerError::Transfer(Eh02Error(e)));
+        if !self.idle_disconnect {
+            return result;
+        }
+        let closed = self.write_control_eh02(self.deselect);
+        let value = result?;
+        closed.map(|()| value)
+    }
+}
+
+#[cfg(feature = "eh02")]
+impl<I2C, D, E> i2c02::Write for BusPort<I2C, D>
+where
+    I2C: i2c02::Write<Error = E>,
+    D: DelayNs,
+    E: core::fmt::Debug,
+{
+    type Error = MultiplexerError<Eh02Error<E>>;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.open_port_eh02()?;
+        let result = i2c02::Write::write(&mut self.bus, address, bytes);
+        self.finish_eh02(result)
+    }
+}
+
+#[cfg(feature = "eh02")]
+impl<I2C, D, E> i2c02::Read for BusPort<I2C, D>
+where
+    I2C: i2c02::Write<Error = E> + i2c02::Read<Error = E>,
+    D: DelayNs,
+    E: core::fmt::Debug,
+{
+    type Error = MultiplexerError<Eh02Error<E>>;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.readable(true)?;
+        self.open_port_eh02()?;
+        let result = i2c02::Read::read(&mut self.bus, address, buffer);
+        self.finish_eh02(result)
+    }
+}
+
+#[cfg(feature = "eh02")]
+impl<I2C, D, E> i2c02::WriteRead for BusPort<I2C, D>
+where
+    I2C: i2c02::Write<Error = E> + i2c02::WriteRead<Error = E>,
+    D: DelayNs,
+    E: core::fmt::Debug,
+{
+    type Error = MultiplexerError<Eh02Error<E>>;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.readable(true)?;
+        self.open_port_eh02()?;
+        let result = i2c02::WriteRead::write_read(&mut self.bus, address, bytes, buffer);
+        self.finish_eh02(result)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C, D> BusPort<I2C, D>
+where
+    I2C: AsyncI2c,
+    D: AsyncDelayNs,
+{
+    pub(crate) async fn open_port_async(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        if !self.pre_select {
+            return Ok(());
+        }
+        if self.verify_before {
+            if self.read_control_async().await? & self.verify_mask == self.port {
+                return Ok(());
+            }
+            self.external_changes = self.external_changes.wrapping_add(1);
+        }
+        self.select_async().await
+    }
+
+    /// Writes the select byte, see [`select`](Self::select)
+    pub async fn select_async(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        let mut attempt = 0;
+        loop {
+            match self.write_control_async(self.port).await {
+                Ok(()) => break,
+                Err(e) if attempt == self.select_retries => return Err(self.select_failed(e)),
+                Err(_) => {}
+            }
+            attempt += 1;
+            self.retries = self.retries.wrapping_add(1);
+            if self.backoff_us > 0 {
+                self.delay.delay_us(self.backoff_us).await;
+            }
+        }
+        if self.verify_select {
+            self.verify_select_async().await?;
+        }
+        if self.settle_ns > 0 {
+            self.delay.delay_ns(self.settle_ns).await;
+        }
+        Ok(())
+    }
+
+    async fn verify_select_async(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        let mut actual = self.read_control_async().await?;
+        if actual & self.verify_mask != self.port {
+            self.write_control_async(self.port)
+                .await
+                .map_err(MultiplexerError::Select)?;
+            actual = self.read_control_async().await?;
+        }
+        if actual & self.verify_mask != self.port {
+            return Err(MultiplexerError::SelectVerificationFailed {
+                expected: self.port,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    async fn read_control_async(&mut self) -> Result<u8, MultiplexerError<I2C::Error>> {
+        let mut read = [0];
+        match self.control_register {
+            Some(register) => {
+                self.bus
+                    .write_read(self.address, &[register], &mut read)
+                    .await
+            }
+            None => self.bus.read(self.address, &mut read).await,
+        }
+        .map_err(MultiplexerError::Select)?;
+        Ok(read[0])
+    }
+
+    /// Disconnects the port from the upstream bus
+    pub async fn close_port_async(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.write_control_async(self.deselect)
+            .await
+            .map_err(MultiplexerError::Select)
+    }
+
+    async fn write_control_async(&mut self, code: u8) -> Result<(), I2C::Error> {
+        match self.control_register {
+            Some(register) => self.bus.write(self.address, &[register, code]).await,
+            None => self.bus.write(self.address, &[code]).await,
+        }
+    }
+
+    /// Disconnects the port after the transfer if enabled, a transfer error is returned over
+    /// a disconnect error
+    async fn finish_async<T>(
+        &mut self,
+        result: Result<T, I2C::Error>,
+    ) -> Result<T, MultiplexerError<I2C::Error>> {
+        let result = result.map_err(MultiplexerError::Transfer);
+        if !self.idle_disconnect {
+            return result;
+        }
+        let closed = self.close_port_async().await;
+        let value = result?;
+        closed.map(|()| value)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C, D> AsyncI2c for BusPort<I2C, D>
+where
+    I2C: AsyncI2c,
+    D: AsyncDelayNs,
+{
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.readable(true)?;
+        self.open_port_async().await?;
+        let result = self.bus.read(address, read).await;
+        self.finish_async(result).await
+    }
+
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.open_port_async().await?;
+        let result = self.bus.write(address, write).await;
+        self.finish_async(result).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.allowed(address.into())?;
+        self.readable(true)?;
+        self.open_port_async().await?;
+        let result = self.bus.write_read(address, write, read).await;
+        self.finish_async(result).await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+        self.allowed(address.into())?;
+        self.readable(
+            operations
+                .iter()
+                .any(|operation| matches!(operation, Operation::Read(_))),
+        )?;
+        self.open_port_async().await?;
+        let result = self.bus.transaction(address, operations).await;
+        self.finish_async(result).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C, D> CachedPort<'_, I2C, D>
+where
+    I2C: AsyncI2c,
+    D: AsyncDelayNs,
+{
+    async fn open_port_async(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        if self.selected.get() != Some(self.port.port) {
+            self.invalidate();
+            self.port.open_port_async().await?;
+            self.selected.set(Some(self.port.port));
+        }
+        Ok(())
+    }
+
+    /// Disconnects the port from the upstream bus
+    pub async fn close_port_async(&mut self) -> Result<(), MultiplexerError<I2C::Error>> {
+        self.invalidate();
+        self.port.close_port_async().await?;
+        self.selected.set(Some(self.port.deselect));
+        Ok(())
+    }
+
+    async fn finish_async<T>(
+        &mut self,
+        result: Result<T, I2C::Error>,
+    ) -> Result<T, MultiplexerError<I2C::Error>> {
+        if result.is_err() {
+            self.invalidate();
+        }
+        let result = result.map_err(MultiplexerError::Transfer);
+        if !self.port.idle_disconnect {
+            return result;
+        }
+        let closed = self.close_port_async().await;
+        let value = result?;
+        closed.map(|()| value)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C, D> AsyncI2c for CachedPort<'_, I2C, D>
+where
+    I2C: AsyncI2c,
+    D: AsyncDelayNs,
+{
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.port.allowed(address.into())?;
+        self.open_port_async().await?;
+        let result = self.port.bus.read(address, read).await;
+        self.finish_async(result).await
+    }
+
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.port.allowed(address.into())?;
+        self.open_port_async().await?;
+        let result = self.port.bus.write(address, write).await;
+        self.finish_async(result).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.port.allowed(address.into())?;
+        self.open_port_async().await?;
+        let result = self.port.bus.write_read(address, write, read).await;
+        self.finish_async(result).await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+        self.port.allowed(address.into())?;
+        self.open_port_async().await?;
+        let result = self.port.bus.transaction(address, operations).await;
+        self.finish_async(result).await
+    }
+}
+
+/// The select write stays seven bit, only the downstream device uses a ten bit address
+#[cfg(feature = "async")]
+impl<I2C, D> AsyncI2c<TenBitAddress> for BusPort<I2C, D>
+where
+    I2C: AsyncI2c + AsyncI2c<TenBitAddress>,
+    D: AsyncDelayNs,
+{
+    async fn read(&mut self, address: TenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.allowed(address)?;
+        self.readable(true)?;
+        self.open_port_async().await?;
+        let result = AsyncI2c::<TenBitAddress>::read(&mut self.bus, address, read).await;
+        self.finish_async(result).await
+    }
+
+    async fn write(&mut self, address: TenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.allowed(address)?;
+        self.open_port_async().await?;
+        let result = AsyncI2c::<TenBitAddress>::write(&mut self.bus, address, write).await;
+        self.finish_async(result).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: TenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.allowed(address)?;
+        self.readable(true)?;
+        self.open_port_async().await?;
+        let result =
+            AsyncI2c::<TenBitAddress>::write_read(&mut self.bus, address, write, read).await;
+        self.finish_async(result).await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: TenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+        self.allowed(address)?;
+        self.readable(
+            operations
+                .iter()
+                .any(|operation| matches!(operation, Operation::Read(_))),
+        )?;
+        self.open_port_async().await?;
+        let result =
+            AsyncI2c::<TenBitAddress>::transaction(&mut self.bus, address, operations).await;
+        self.finish_async(result).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use crate::chip::ChannelEncoder;
+    use crate::prelude::*;
+    use alloc::{format, vec, vec::Vec};
+    use core::cell::RefCell;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use embedded_hal::i2c::{ErrorKind, I2c, NoAcknowledgeSource, Operation};
+    use embedded_hal_bus::i2c::RefCellDevice;
+    use embedded_hal_mock::eh1::delay::{CheckedDelay, Transaction as DelayTransaction};
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+    use rstest::*;
+
+    #[test]
+    fn multi_port_write() {
+        let multiplexer_addr = 0x01;
+        let component_addr = 0x02;
+
+        // Use port 1, 3, 2, 4 in that order
+        let ports = [
+            (0, 0b000_0001),
+            (2, 0b000_0100),
+            (1, 0b000_0010),
+            (3, 0b000_1000),
+        ];
+
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![ports[0].1]),
+            Transaction::write(component_addr, vec![0x05, 0x43]),
+            Transaction::write(multiplexer_addr, vec![ports[1].1]),
+            Transaction::write(component_addr, vec![0x55]),
+            Transaction::write(multiplexer_addr, vec![ports[2].1]),
+            Transaction::write(component_addr, vec![0x07, 0x39, 0x87]),
+            Transaction::write(multiplexer_addr, vec![ports[3].1]),
+            Transaction::write(component_addr, vec![0x45, 0x48]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new().with_address(multiplexer_addr);
+
+        {
+            let mut multiplexed_i2c_a = multiplexer.new_port(RefCellDevice::new(&i2c), ports[0].0);
+            let mut multiplexed_i2c_b = multiplexer.new_port(RefCellDevice::new(&i2c), ports[1].0);
+            let mut multiplexed_i2c_c = multiplexer.new_port(RefCellDevice::new(&i2c), ports[2].0);
+            let mut multiplexed_i2c_d = multiplexer.new_port(RefCellDevice::new(&i2c), ports[3].0);
+
+            assert!(multiplexed_i2c_a
+                .write(component_addr, &[0x05, 0x43])
+                .is_ok());
+            assert!(multiplexed_i2c_b.write(component_addr, &[0x55]).is_ok());
+            assert!(multiplexed_i2c_c
+                .write(component_addr, &[0x07, 0x39, 0x87])
+                .is_ok());
+            assert!(multiplexed_i2c_d
+                .write(component_addr, &[0x45, 0x48])
+                .is_ok());
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn set_address() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x75, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+        ]);
+        let mut multiplexer = MultiplexerBus::new();
+        assert_eq!(
+            multiplexer.set_address(0xF5),
+            Err(MultiplexerError::InvalidAddress(0xF5))
+        );
+        assert!(multiplexer.set_address(0x75).is_ok());
+        assert_eq!(multiplexer.address(), 0x75);
+        let mut port = multiplexer.new_port(i2c, 1);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        port.into_inner().done();
+    }
+
+    #[test]
+    #[should_panic(expected = "port 4 out of range")]
+    fn new_port_out_of_range() {
+        MultiplexerBus::new().new_port((), 4);
+    }
+
+    #[test]
+    fn idle_disconnect() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::read(0x20, vec![0x02]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            // Cached ports record the disconnect
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x21, vec![0x03]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x21, vec![0x04]),
+            Transaction::write(0x70, vec![0b0000_0000]).with_error(ErrorKind::Bus),
+        ]));
+        let multiplexer = MultiplexerBus::new()
+            .with_idle_disconnect(true)
+            .with_cache();
+        let mut port = multiplexer.bus().new_port(RefCellDevice::new(&i2c), 1);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        assert_eq!(
+            port.read(0x20, &mut [0]),
+            Err(MultiplexerError::Transfer(ErrorKind::Bus))
+        );
+        let mut cached = multiplexer.new_cached_port(RefCellDevice::new(&i2c), 3);
+        assert!(cached.write(0x21, &[0x03]).is_ok());
+        assert_eq!(
+            cached.write(0x21, &[0x04]),
+            Err(MultiplexerError::Select(ErrorKind::Bus))
+        );
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn broadcast_port() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_1111]),
+            Transaction::write(0x3C, vec![0x00, 0xAF]),
+            Transaction::write(0x70, vec![0b0000_1111]),
+            Transaction::transaction_start(0x3C),
+            Transaction::write(0x3C, vec![0x40]),
+            Transaction::write(0x3C, vec![0xFF]),
+            Transaction::transaction_end(0x3C),
+            Transaction::write(0x70, vec![0b1111_1111]),
+            Transaction::write(0x3C, vec![0x00, 0xAE]),
+        ]));
+        let mut port = MultiplexerBus::new().broadcast_port(RefCellDevice::new(&i2c));
+        assert!(port.write(0x3C, &[0x00, 0xAF]).is_ok());
+        assert!(port
+            .transaction(
+                0x3C,
+                &mut [Operation::Write(&[0x40]), Operation::Write(&[0xFF])]
+            )
+            .is_ok());
+        let mut read = [0];
+        assert_eq!(
+            port.read(0x3C, &mut read),
+            Err(MultiplexerError::BroadcastReadUnsupported)
+        );
+        assert_eq!(
+            port.write_read(0x3C, &[0x00], &mut read),
+            Err(MultiplexerError::BroadcastReadUnsupported)
+        );
+        assert_eq!(
+            port.transaction(
+                0x3C,
+                &mut [Operation::Write(&[0x00]), Operation::Read(&mut read)]
+            ),
+            Err(MultiplexerError::BroadcastReadUnsupported)
+        );
+        let mut port = MultiplexerBus::new()
+            .with_chip(Tca9548)
+            .broadcast_port(RefCellDevice::new(&i2c));
+        assert!(port.write(0x3C, &[0x00, 0xAE]).is_ok());
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn group_port() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0101]),
+            Transaction::write(0x48, vec![0x01, 0x60]),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::read(0x48, vec![0x60]),
+            Transaction::write(0x70, vec![0b0000_0110]),
+            Transaction::write(0x48, vec![0x02]),
+        ]));
+        let multiplexer = MultiplexerBus::new();
+        let mut left = multiplexer
+            .new_group(RefCellDevice::new(&i2c), 0b0000_0101)
+            .unwrap();
+        assert!(left.write(0x48, &[0x01, 0x60]).is_ok());
+        assert_eq!(
+            left.read(0x48, &mut [0]),
+            Err(MultiplexerError::BroadcastReadUnsupported)
+        );
+        // A single port can be read
+        let mut single = multiplexer
+            .new_group(RefCellDevice::new(&i2c), 0b0000_1000)
+            .unwrap();
+        assert!(single.read(0x48, &mut [0]).is_ok());
+        for mask in [0b0000_0000, 0b0001_0001] {
+            assert!(matches!(
+                multiplexer.new_group(RefCellDevice::new(&i2c), mask),
+                Err(MultiplexerError::UnsupportedPorts(m)) if m == mask
+            ));
+        }
+        let mux = MultiplexerBus::new().with_chip(Pca9544);
+        assert!(matches!(
+            mux.new_group(RefCellDevice::new(&i2c), 0b0000_0011),
+            Err(MultiplexerError::UnsupportedPorts(0b0000_0011))
+        ));
+        let mut middle = multiplexer
+            .new_group(RefCellDevice::new(&i2c), 0b0000_0110)
+            .unwrap();
+        assert!(middle.write(0x48, &[0x02]).is_ok());
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn deselect_on_drop() {
+        static FAILED: AtomicBool = AtomicBool::new(false);
+
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0000]).with_error(ErrorKind::Bus),
+        ]));
+        let multiplexer = MultiplexerBus::new();
+        {
+            let mut port = multiplexer
+                .new_port(RefCellDevice::new(&i2c), 2)
+                .deselect_on_drop();
+            assert!(port.write(0x20, &[0x01]).is_ok());
+        }
+        assert!(!FAILED.load(Ordering::Relaxed));
+        drop(
+            multiplexer
+                .new_port(RefCellDevice::new(&i2c), 2)
+                .deselect_on_drop()
+                .with_drop_error_handler(|e| {
+                    assert_eq!(e, MultiplexerError::Select(ErrorKind::Bus));
+                    FAILED.store(true, Ordering::Relaxed);
+                }),
+        );
+        assert!(FAILED.load(Ordering::Relaxed));
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn cached_ports() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::read(0x20, vec![0x02]),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x21, vec![0x03]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x04]).with_error(ErrorKind::Bus),
+            // The failed transfer forgot the selected port
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x05]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_1000]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x21, vec![0x06]),
+        ]));
+        let multiplexer = MultiplexerBus::new().with_cache();
+        let mut first = multiplexer.new_cached_port(RefCellDevice::new(&i2c), 1);
+        let mut third = multiplexer.new_cached_port(RefCellDevice::new(&i2c), 3);
+        assert!(first.write(0x20, &[0x01]).is_ok());
+        let mut read = [0];
+        assert!(first.read(0x20, &mut read).is_ok());
+        assert!(third.write(0x21, &[0x03]).is_ok());
+        assert_eq!(
+            first.write(0x20, &[0x04]),
+            Err(MultiplexerError::Transfer(ErrorKind::Bus))
+        );
+        assert!(first.write(0x20, &[0x05]).is_ok());
+        assert!(first.close_port().is_ok());
+        assert_eq!(
+            third.write(0x21, &[0x06]),
+            Err(MultiplexerError::Select(ErrorKind::Bus))
+        );
+        assert!(third.write(0x21, &[0x06]).is_ok());
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn into_inner() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x20, vec![0x02]),
+        ]);
+        let multiplexer = MultiplexerBus::new();
+        let mut port = multiplexer.new_port(i2c, 2);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        let mut port = multiplexer.new_port(port.into_inner(), 0);
+        assert!(port.write(0x20, &[0x02]).is_ok());
+        port.into_inner().done();
+    }
+
+    #[rstest]
+    #[case(MultiplexerBus::new().ports(()).map(|port| port.mask()).to_vec(), 4)]
+    #[case(MultiplexerBus::new().with_chip(Tca9548).ports(()).map(|port| port.mask()).to_vec(), 8)]
+    fn ports(#[case] masks: vec::Vec<u8>, #[case] channels: usize) {
+        assert_eq!(masks.len(), channels);
+        for (port, mask) in masks.into_iter().enumerate() {
+            assert_eq!(mask, 1 << port);
+        }
+    }
+
+    #[test]
+    fn ports_select() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x20, vec![0x00]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x20, vec![0x02]),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x20, vec![0x03]),
+        ]);
+        for (port, mut bus) in MultiplexerBus::new()
+            .ports(i2c.clone())
+            .into_iter()
+            .enumerate()
+        {
+            assert!(bus.write(0x20, &[port as u8]).is_ok());
+        }
+        i2c.done();
+    }
+
+    #[test]
+    fn ports_with() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x20, vec![0x02]),
+        ]));
+        let [_, _, mut third, _] = MultiplexerBus::new().ports_with(|_| RefCellDevice::new(&i2c));
+        assert!(third.write(0x20, &[0x02]).is_ok());
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn set_port() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::read(0x20, vec![0x02]),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x20, vec![0x03]),
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x20, vec![0x04]),
+        ]));
+        let multiplexer = MultiplexerBus::new().with_cache();
+        let mut port = multiplexer.bus().new_port(RefCellDevice::new(&i2c), 0);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        assert_eq!(port.set_port(4), Err(MultiplexerError::InvalidPort(4)));
+        assert!(port.set_port(2).is_ok());
+        assert_eq!((port.port(), port.mask()), (2, 0b0000_0100));
+        // Reads work again after leaving a group
+        let mut port = multiplexer
+            .bus()
+            .new_group(RefCellDevice::new(&i2c), 0b0000_0011)
+            .unwrap()
+            .with_port(2)
+            .unwrap();
+        assert!(port.read(0x20, &mut [0]).is_ok());
+        let mut cached = multiplexer.new_cached_port(RefCellDevice::new(&i2c), 3);
+        assert!(cached.write(0x20, &[0x03]).is_ok());
+        // The cache is cleared, so even the same port is selected again
+        assert!(cached.set_port(3).is_ok());
+        assert!(cached.write(0x20, &[0x04]).is_ok());
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn settle_delay() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x20, vec![0x02]),
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x04]),
+        ]));
+        let mut delay = CheckedDelay::new(&[DelayTransaction::delay_ns(50_000)]);
+        let multiplexer = MultiplexerBus::new().with_cache();
+        let mut port = multiplexer
+            .new_cached_port(RefCellDevice::new(&i2c), 1)
+            .with_settle_delay(50_000, &mut delay);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        // Already selected, no delay
+        assert!(port.write(0x20, &[0x02]).is_ok());
+        port.invalidate();
+        // No delay after a failed select
+        assert!(port.write(0x20, &[0x03]).is_err());
+        // Zero never calls the delay
+        let mut port = multiplexer
+            .bus()
+            .new_port(RefCellDevice::new(&i2c), 1)
+            .with_settle_delay(0, &mut delay);
+        assert!(port.write(0x20, &[0x04]).is_ok());
+        delay.done();
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn select_retries() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Other),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x05]).with_error(ErrorKind::Other),
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Other),
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Other),
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Other),
+        ]));
+        let multiplexer = MultiplexerBus::new().with_select_retries(2);
+        let mut port = multiplexer.new_port(RefCellDevice::new(&i2c), 1);
+        // The select is retried, the downstream write isn't
+        assert_eq!(
+            port.write(0x20, &[0x05]),
+            Err(MultiplexerError::Transfer(ErrorKind::Other))
+        );
+        assert_eq!(port.retries(), 1);
+        assert_eq!(
+            port.write(0x20, &[0x05]),
+            Err(MultiplexerError::SelectFailed {
+                attempts: 3,
+                error: ErrorKind::Other
+            })
+        );
+        assert_eq!(port.retries(), 3);
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn new_port_checked() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x71, vec![0b0000_0100]).with_error(ErrorKind::Other),
+        ]);
+        let port = MultiplexerBus::new().new_port_checked(&mut i2c, 2);
+        assert!(port.is_ok());
+        let port = MultiplexerBus::new()
+            .with_address(0x71)
+            .new_port_checked(&mut i2c, 2);
+        assert_eq!(
+            port.map(|_| ()).map_err(|(_, e)| e),
+            Err(MultiplexerError::Select(ErrorKind::Other))
+        );
+        let port = MultiplexerBus::new().new_port_checked(&mut i2c, 4);
+        assert_eq!(
+            port.map(|_| ()).map_err(|(_, e)| e),
+            Err(MultiplexerError::InvalidPort(4))
+        );
+        i2c.done();
+    }
+
+    #[rstest]
+    #[case(None, Ok(true))]
+    #[case(
+        Some(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)),
+        Ok(false)
+    )]
+    #[case(
+        Some(ErrorKind::ArbitrationLoss),
+        Err(MultiplexerError::Transfer(ErrorKind::ArbitrationLoss))
+    )]
+    fn probe(
+        #[case] error: Option<ErrorKind>,
+        #[case] expected: Result<bool, MultiplexerError<ErrorKind>>,
+    ) {
+        let mut probe = Transaction::write(0x20, vec![]);
+        if let Some(error) = error {
+            probe = probe.with_error(error);
+        }
+        let mut i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0010]), probe]);
+        let mut port = MultiplexerBus::new().new_port(&mut i2c, 1);
+        assert_eq!(port.probe(0x20), expected);
+        i2c.done();
+    }
+
+    #[test]
+    fn probe_select_error() {
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let mut i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0010]).with_error(nack)]);
+        let mut port = MultiplexerBus::new().new_port(&mut i2c, 1);
+        assert_eq!(port.probe(0x20), Err(MultiplexerError::Select(nack)));
+        i2c.done();
+    }
+
+    #[test]
+    fn allowed_addresses() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x29, vec![0x01]),
+        ]);
+        let mut port = MultiplexerBus::new()
+            .new_port(&mut i2c, 1)
+            .with_allowed_addresses(&[0x29]);
+        assert_eq!(
+            port.write(0x48, &[0x01]),
+            Err(MultiplexerError::AddressNotAllowed(0x48))
+        );
+        assert_eq!(
+            port.read(0x48, &mut [0]),
+            Err(MultiplexerError::AddressNotAllowed(0x48))
+        );
+        assert_eq!(
+            port.transaction(0x48, &mut [Operation::Write(&[0x01])]),
+            Err(MultiplexerError::AddressNotAllowed(0x48))
+        );
+        assert!(port.write(0x29, &[0x01]).is_ok());
+        i2c.done();
+    }
+
+    #[test]
+    fn allowed_addresses_empty() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x48, vec![0x01]),
+        ]);
+        let mut port = MultiplexerBus::new()
+            .new_port(&mut i2c, 1)
+            .with_allowed_addresses(&[]);
+        assert!(port.write(0x48, &[0x01]).is_ok());
+        i2c.done();
+    }
+
+    #[test]
+    fn empty_transaction() {
+        let mut i2c = Mock::new(&[]);
+        let mut delay = CheckedDelay::new(&[]);
+        let multiplexer = MultiplexerBus::new()
+            .with_idle_disconnect(true)
+            .with_cache();
+        let mut port = multiplexer
+            .bus()
+            .new_port(&mut i2c, 1)
+            .with_settle_delay(50_000, &mut delay);
+        assert!(port.transaction(0x20, &mut []).is_ok());
+        let mut cached = multiplexer.new_cached_port(&mut i2c, 1);
+        assert!(cached.transaction(0x20, &mut []).is_ok());
+        delay.done();
+        i2c.done();
+    }
+
+    #[test]
+    fn pre_select() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::read(0x20, vec![0x02]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x03]),
+        ]);
+        let mut port = MultiplexerBus::new()
+            .new_port(&mut i2c, 1)
+            .with_pre_select(false);
+        assert!(port.select().is_ok());
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        let mut read = [0];
+        assert!(port.read(0x20, &mut read).is_ok());
+        assert_eq!(read, [0x02]);
+        // Re-asserted after a disturbance
+        assert!(port.select().is_ok());
+        assert!(port.write(0x20, &[0x03]).is_ok());
+        i2c.done();
+    }
+
+    #[test]
+    fn observer() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01, 0x02]),
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write_read(0x20, vec![0x03], vec![0x04, 0x05])
+                .with_error(ErrorKind::ArbitrationLoss),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::transaction_start(0x20),
+            Transaction::write(0x20, vec![0x06]),
+            Transaction::read(0x20, vec![0x07]),
+            Transaction::transaction_end(0x20),
+        ]);
+        let mut events = heapless::Vec::<BusEvent, 8>::new();
+        {
+            let mut port = MultiplexerBus::new()
+                .new_port(&mut i2c, 1)
+                .with_allowed_addresses(&[0x20])
+                .with_observer(|event| events.push(event).unwrap());
+            assert!(port.write(0x20, &[0x01, 0x02]).is_ok());
+            assert!(port.read(0x20, &mut [0; 3]).is_err());
+            assert!(port.write_read(0x20, &[0x03], &mut [0; 2]).is_err());
+            let mut read = [0];
+            assert!(port
+                .transaction(
+                    0x20,
+                    &mut [Operation::Write(&[0x06]), Operation::Read(&mut read)]
+                )
+                .is_ok());
+            assert!(port.write(0x48, &[]).is_err());
+        }
+        let event = |operation, len, outcome| BusEvent {
+            port: 1,
+            address: 0x20,
+            operation,
+            len,
+            outcome,
+        };
+        assert_eq!(
+            events,
+            [
+                event(BusOperation::Write, 2, BusOutcome::Ok),
+                event(BusOperation::Read, 3, BusOutcome::Select(ErrorKind::Bus)),
+                event(
+                    BusOperation::WriteRead,
+                    3,
+                    BusOutcome::Transfer(ErrorKind::ArbitrationLoss)
+                ),
+                event(BusOperation::Transaction, 2, BusOutcome::Ok),
+                BusEvent {
+                    address: 0x48,
+                    ..event(BusOperation::Write, 0, BusOutcome::Rejected)
+                },
+            ]
+        );
+        i2c.done();
+    }
+
+    #[test]
+    fn error_context() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x72, vec![0b0000_0100]).with_error(ErrorKind::Bus),
+            Transaction::write(0x72, vec![0b0000_0100]),
+            Transaction::write(0x20, vec![0x01]).with_error(ErrorKind::Other),
+        ]);
+        let mut port = MultiplexerBus::new()
+            .with_address(0x72)
+            .new_port(&mut i2c, 2)
+            .with_allowed_addresses(&[0x20])
+            .with_error_context();
+        let select = port.write(0x20, &[0x01]).unwrap_err();
+        assert_eq!(
+            select,
+            PortOpError {
+                mux: 0x72,
+                port: 2,
+                stage: PortStage::Select,
+                error: MultiplexerError::Select(ErrorKind::Bus),
+            }
+        );
+        assert_eq!(embedded_hal::i2c::Error::kind(&select), ErrorKind::Bus);
+        assert_eq!(
+            format!("{}", select),
+            "Select on port 2 of the multiplexer at 0x72 failed: Channel select I2C error"
+        );
+        let transfer = port.write(0x20, &[0x01]).unwrap_err();
+        assert_eq!(transfer.stage, PortStage::Transfer);
+        assert_eq!(transfer.error, MultiplexerError::Transfer(ErrorKind::Other));
+        assert_eq!(port.write(0x48, &[]).unwrap_err().stage, PortStage::Check);
+        i2c.done();
+    }
+
+    #[test]
+    fn erased() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x20, vec![0x01]),
+        ]));
+        let mut other = Mock::new(&[
+            Transaction::write(0x71, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]).with_error(ErrorKind::ArbitrationLoss),
+        ]);
+        let mut first = MultiplexerBus::new()
+            .new_port(RefCellDevice::new(&i2c), 0)
+            .into_erased();
+        let mut second = MultiplexerBus::new()
+            .with_address(0x71)
+            .new_port(&mut other, 1)
+            .into_erased();
+        let mut ports = heapless::Vec::<&mut dyn I2c<Error = ErasedPortError>, 2>::new();
+        ports.push(&mut first).ok().unwrap();
+        ports.push(&mut second).ok().unwrap();
+        assert!(ports[0].write(0x20, &[0x01]).is_ok());
+        assert_eq!(
+            ports[1].write(0x20, &[0x01]),
+            Err(ErasedPortError {
+                mux: 0x71,
+                port: 1,
+                stage: PortStage::Transfer,
+                kind: ErrorKind::ArbitrationLoss,
+            })
+        );
+        drop(ports);
+        other.done();
+        i2c.into_inner().done();
+    }
+
+    static BUS: MultiplexerBus = MultiplexerBus::new().with_address(crate::ADDRESS_001);
+
+    #[test]
+    fn static_bus() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x71, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+        ]);
+        let mut port = BUS.new_port(&mut i2c, 1);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        i2c.done();
+    }
+
+    #[test]
+    fn const_bus() {
+        const _: () = assert!(
+            MultiplexerBus::new()
+                .with_address_pins(true, false, true)
+                .address()
+                == crate::ADDRESS_101
+        );
+        let multiplexer = const { MultiplexerBus::new().with_address(crate::ADDRESS_011) };
+        assert_eq!(multiplexer.address(), 0x73);
+        assert_eq!(
+            MultiplexerBus::new().deselect,
+            <Pca9546 as Chip>::Encoder::deselect()
+        );
+    }
+
+    #[test]
+    fn disable_all() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x72, vec![0b0000_0000]),
+            Transaction::write(0x72, vec![0b0000_0000]).with_error(ErrorKind::Bus),
+        ]);
+        let multiplexer = MultiplexerBus::new().with_address(0x72);
+        assert!(multiplexer.disable_all(&mut i2c).is_ok());
+        assert_eq!(
+            multiplexer.disable_all(&mut i2c),
+            Err(MultiplexerError::Select(ErrorKind::Bus))
+        );
+        i2c.done();
+    }
+
+    #[rstest]
+    #[case(0b0000_0101, vec![Transaction::write(0x70, vec![0b0000_0101])], Ok(()))]
+    #[case(0, vec![], Err(MultiplexerError::UnsupportedPorts(0)))]
+    #[case(0b0001_0000, vec![], Err(MultiplexerError::UnsupportedPorts(0b0001_0000)))]
+    fn select(
+        #[case] mask: u8,
+        #[case] transactions: Vec<Transaction>,
+        #[case] expected: Result<(), MultiplexerError<ErrorKind>>,
+    ) {
+        let mut i2c = Mock::new(&transactions);
+        assert_eq!(MultiplexerBus::new().select(&mut i2c, mask), expected);
+        i2c.done();
+    }
+
+    #[test]
+    fn select_cached() {
+        let i2c = RefCell::new(Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x02]),
+        ]));
+        let multiplexer = MultiplexerBus::new().with_cache();
+        assert!(multiplexer
+            .select(&mut RefCellDevice::new(&i2c), 0b0000_0010)
+            .is_ok());
+        let mut port = multiplexer.new_cached_port(RefCellDevice::new(&i2c), 1);
+        // Already selected by the raw write
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        assert!(multiplexer
+            .disable_all(&mut RefCellDevice::new(&i2c))
+            .is_ok());
+        assert!(port.write(0x20, &[0x02]).is_ok());
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn verify_before_operation() {
+        let mut i2c = Mock::new(&[
+            Transaction::read(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::read(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x02]),
+            // Another master selected port 3
+            Transaction::read(0x70, vec![0b0000_1000]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x03]),
+            Transaction::read(0x70, vec![0b0000_0010]).with_error(ErrorKind::Bus),
+        ]);
+        let mut port = MultiplexerBus::new()
+            .new_port(&mut i2c, 1)
+            .with_verify_before_operation(true);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        assert!(port.write(0x20, &[0x02]).is_ok());
+        assert_eq!(port.external_changes(), 1);
+        assert!(port.write(0x20, &[0x03]).is_ok());
+        assert_eq!(port.external_changes(), 2);
+        assert_eq!(
+            port.write(0x20, &[0x04]),
+            Err(MultiplexerError::Select(ErrorKind::Bus))
+        );
+        i2c.done();
+    }
+
+    #[test]
+    fn channels() {
+        fn check<C: Chip>(multiplexer: MultiplexerBus<C>, channels: u8) {
+            let mut i2c = Mock::new(&[]);
+            assert_eq!(multiplexer.channels(), channels);
+            assert!(multiplexer.try_new_port(&mut i2c, channels - 1).is_ok());
+            assert_eq!(
+                multiplexer.try_new_port(&mut i2c, channels).map(|_| ()),
+                Err(MultiplexerError::InvalidPort(channels))
+            );
+            i2c.done();
+        }
+        check(MultiplexerBus::new().with_chip(Pca9543), 2);
+        check(MultiplexerBus::new(), 4);
+        check(MultiplexerBus::new().with_chip(Tca9548), 8);
+    }
+
+    #[test]
+    fn ack_poll() {
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x50, vec![]).with_error(nack),
+            Transaction::write(0x50, vec![]).with_error(nack),
+            Transaction::write(0x50, vec![]),
+        ]);
+        let mut delay = CheckedDelay::new(&[
+            DelayTransaction::delay_us(100),
+            DelayTransaction::delay_us(100),
+        ]);
+        let mut port = MultiplexerBus::new().new_port(&mut i2c, 1);
+        assert!(port.ack_poll(0x50, 5, &mut delay, 100).is_ok());
+        delay.done();
+        i2c.done();
+    }
+
+    #[rstest]
+    #[case(
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+        3,
+        MultiplexerError::DeviceBusyTimeout(0x50)
+    )]
+    #[case(
+        ErrorKind::ArbitrationLoss,
+        1,
+        MultiplexerError::Transfer(ErrorKind::ArbitrationLoss)
+    )]
+    fn ack_poll_error(
+        #[case] error: ErrorKind,
+        #[case] polls: usize,
+        #[case] expected: MultiplexerError<ErrorKind>,
+    ) {
+        let mut transactions = vec![Transaction::write(0x70, vec![0b0000_0010])];
+        transactions.extend((0..polls).map(|_| Transaction::write(0x50, vec![]).with_error(error)));
+        let mut i2c = Mock::new(&transactions);
+        let mut delay = CheckedDelay::new(&vec![DelayTransaction::delay_us(100); polls - 1]);
+        let mut port = MultiplexerBus::new().new_port(&mut i2c, 1);
+        assert_eq!(port.ack_poll(0x50, 3, &mut delay, 100), Err(expected));
+        delay.done();
+        i2c.done();
+    }
+
+    #[test]
+    fn const_port() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+        ]);
+        fn driver(mut i2c: ConstPort<Mock, 1>) -> Mock {
+            assert!(i2c.write(0x20, &[0x01]).is_ok());
+            i2c.into_port().into_inner()
+        }
+        driver(MultiplexerBus::new().port::<1, _>(i2c)).done();
+    }
+
+    #[test]
+    fn verified_select() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::read(0x70, vec![0b0000_0011]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            // Interrupt bits don't count as a mismatch
+            Transaction::read(0x70, vec![0b0100_0010]),
+            Transaction::write(0x20, vec![0x05]),
+        ]);
+        let mut delay = CheckedDelay::new(&[DelayTransaction::delay_ns(50_000)]);
+        let mut port = MultiplexerBus::new()
+            .with_chip(Pca9545)
+            .port::<1, _>(&mut i2c)
+            .with_settle_delay(50_000, &mut delay)
+            .with_verified_select(true);
+        assert!(port.write(0x20, &[0x05]).is_ok());
+        delay.done();
+        i2c.done();
+    }
+
+    #[test]
+    fn verified_select_mismatch() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x74, vec![0x03, 0b0000_0101]),
+            Transaction::write_read(0x74, vec![0x03], vec![0b0000_0100]),
+            Transaction::write(0x74, vec![0x03, 0b0000_0101]),
+            Transaction::write_read(0x74, vec![0x03], vec![0b0000_0100]),
+        ]);
+        let mut port = MultiplexerBus::new()
+            .with_chip(Pca9544)
+            .with_address(0x74)
+            .with_control_register(0x03)
+            .new_port(&mut i2c, 1)
+            .with_verified_select(true);
+        assert_eq!(
+            port.write(0x20, &[0x05]),
+            Err(MultiplexerError::SelectVerificationFailed {
+                expected: 0b0000_0101,
+                actual: 0b0000_0100,
+            })
+        );
+        i2c.done();
+    }
+
+    #[test]
+    fn const_port_builders() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x20, vec![0x01]),
+        ]);
+        let mut delay = CheckedDelay::new(&[
+            DelayTransaction::delay_us(10),
+            DelayTransaction::delay_ns(50_000),
+        ]);
+        let mut port = MultiplexerBus::new()
+            .port::<1, _>(i2c)
+            .with_settle_delay(50_000, &mut delay)
+            .with_select_retries(1, 10);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        assert_eq!(port.as_port().retries(), 1);
+        port.into_port().into_inner().done();
+        delay.done();
+    }
+
+    #[test]
+    fn debug() {
+        let multiplexer = MultiplexerBus::new().with_chip(Tca9548).with_address(0x72);
+        assert_eq!(
+            format!("{:?}", multiplexer),
+            "MultiplexerBus { address: 0x72, channels: 8 }"
+        );
+        let port = multiplexer.new_port((), 5);
+        assert_eq!(
+            (port.port(), port.mask(), port.multiplexer_address()),
+            (5, 0b0010_0000, 0x72)
+        );
+        assert_eq!(
+            format!("{:?}", port),
+            "BusPort { port: 5, mask: 0b00100000, multiplexer_address: 0x72 }"
+        );
+    }
+
+    #[test]
+    fn release() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_1000]),
+            Transaction::write(0x20, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0000]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x20, vec![0x02]),
+        ]);
+        let multiplexer = MultiplexerBus::new();
+        let mut port = multiplexer.new_port(&mut i2c, 3);
+        assert!(port.write(0x20, &[0x01]).is_ok());
+        let (port, e) = port.deselect_and_release().err().unwrap();
+        assert_eq!(e, MultiplexerError::Select(ErrorKind::Bus));
+        let bus = port.deselect_and_release().ok().unwrap();
+        let (bus, port) = multiplexer.new_port(bus, 3).release();
+        assert_eq!(port, 3);
+        let mut port = multiplexer.new_port(bus, port - 1);
+        assert!(port.write(0x20, &[0x02]).is_ok());
+        i2c.done();
+    }
+
+    #[test]
+    fn multi_port_read() {
+        let multiplexer_addr = 0x01;
+        let component_addr = 0x02;
+
+        // Use port 1, 3, 2, 4 in that order
+        let ports = [
+            (0, 0b000_0001),
+            (2, 0b000_0100),
+            (1, 0b000_0010),
+            (3, 0b000_1000),
+        ];
+
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![ports[0].1]),
+            Transaction::read(component_addr, vec![0x05, 0x43]),
+            Transaction::write(multiplexer_addr, vec![ports[1].1]),
+            Transaction::read(component_addr, vec![0x55]),
+            Transaction::write(multiplexer_addr, vec![ports[2].1]),
+            Transaction::read(component_addr, vec![0x07, 0x39, 0x87]),
+            Transaction::write(multiplexer_addr, vec![ports[3].1]),
+            Transaction::read(component_addr, vec![0x45, 0x48]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new().with_address(multiplexer_addr);
+
+        {
+            let mut multiplexed_i2c_a = multiplexer.new_port(RefCellDevice::new(&i2c), ports[0].0);
+            let mut multiplexed_i2c_b = multiplexer.new_port(RefCellDevice::new(&i2c), ports[1].0);
+            let mut multiplexed_i2c_c = multiplexer.new_port(RefCellDevice::new(&i2c), ports[2].0);
+            let mut multiplexed_i2c_d = multiplexer.new_port(RefCellDevice::new(&i2c), ports[3].0);
+
+            let mut ma = [0; 2];
+            assert!(multiplexed_i2c_a.read(component_addr, &mut ma).is_ok());
+            assert_eq!(ma, [0x05, 0x43]);
+
+            let mut mb = [0; 1];
+            assert!(multiplexed_i2c_b.read(component_addr, &mut mb).is_ok());
             assert_eq!(mb, [0x55]);
 
-            let mut mc = [0; 3];
-            assert!(multiplexed_i2c_c.read(component_addr, &mut mc).is_ok());
-            assert_eq!(mc, [0x07, 0x39, 0x87]);
+            let mut mc = [0; 3];
+            assert!(multiplexed_i2c_c.read(component_addr, &mut mc).is_ok());
+            assert_eq!(mc, [0x07, 0x39, 0x87]);
+
+            let mut md = [0; 2];
+            assert!(multiplexed_i2c_d.read(component_addr, &mut md).is_ok());
+            assert_eq!(md, [0x45, 0x48]);
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn multi_port_read_write() {
+        let multiplexer_addr = 0x01;
+        let component_addr = 0x02;
+
+        // Use port 1, 3, 2, 4 in that order
+        let ports = [
+            (0, 0b000_0001),
+            (2, 0b000_0100),
+            (1, 0b000_0010),
+            (3, 0b000_1000),
+        ];
+
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![ports[0].1]),
+            Transaction::write_read(component_addr, vec![0x05, 0x43], vec![0x33, 0x43]),
+            Transaction::write(multiplexer_addr, vec![ports[1].1]),
+            Transaction::write_read(component_addr, vec![0x55], vec![0x33, 0x43]),
+            Transaction::write(multiplexer_addr, vec![ports[2].1]),
+            Transaction::write_read(component_addr, vec![0x07, 0x39, 0x87], vec![0x33, 0x43]),
+            Transaction::write(multiplexer_addr, vec![ports[3].1]),
+            Transaction::write_read(component_addr, vec![0x45, 0x48], vec![0x33, 0x43]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new().with_address(multiplexer_addr);
+
+        {
+            let mut multiplexed_i2c_a = multiplexer.new_port(RefCellDevice::new(&i2c), ports[0].0);
+            let mut multiplexed_i2c_b = multiplexer.new_port(RefCellDevice::new(&i2c), ports[1].0);
+            let mut multiplexed_i2c_c = multiplexer.new_port(RefCellDevice::new(&i2c), ports[2].0);
+            let mut multiplexed_i2c_d = multiplexer.new_port(RefCellDevice::new(&i2c), ports[3].0);
+
+            let mut ma = [0x33, 0x43];
+            assert!(multiplexed_i2c_a
+                .write_read(component_addr, &[0x05, 0x43], &mut ma)
+                .is_ok());
+            assert_eq!(ma, [0x33, 0x43]);
+
+            let mut mb = [0x33, 0x43];
+            assert!(multiplexed_i2c_b
+                .write_read(component_addr, &[0x55], &mut mb)
+                .is_ok());
+            assert_eq!(mb, [0x33, 0x43]);
+
+            let mut mc = [0x33, 0x43];
+            assert!(multiplexed_i2c_c
+                .write_read(component_addr, &[0x07, 0x39, 0x87], &mut mc)
+                .is_ok());
+            assert_eq!(mc, [0x33, 0x43]);
+
+            let mut md = [0x33, 0x43];
+            assert!(multiplexed_i2c_d
+                .write_read(component_addr, &[0x45, 0x48], &mut md)
+                .is_ok());
+            assert_eq!(md, [0x33, 0x43]);
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn eight_channel_port() {
+        let multiplexer_addr = 0x01;
+        let component_addr = 0x02;
+
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![0b1000_0000]),
+            Transaction::write(component_addr, vec![0x05]),
+            Transaction::write(multiplexer_addr, vec![0b0001_0000]),
+            Transaction::write(component_addr, vec![0x06]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new()
+            .with_chip(Tca9548)
+            .with_address(multiplexer_addr);
+
+        {
+            let mut multiplexed_i2c_a = multiplexer.new_port(RefCellDevice::new(&i2c), 7);
+            let mut multiplexed_i2c_b = multiplexer.new_port(RefCellDevice::new(&i2c), 4);
+
+            assert!(multiplexed_i2c_a.write(component_addr, &[0x05]).is_ok());
+            assert!(multiplexed_i2c_b.write(component_addr, &[0x06]).is_ok());
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn single_channel_port() {
+        let multiplexer_addr = 0x01;
+        let component_addr = 0x02;
+
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![0b0000_0111]),
+            Transaction::read(component_addr, vec![0x05]),
+            Transaction::write(multiplexer_addr, vec![0b0000_0100]),
+            Transaction::read(component_addr, vec![0x06]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new()
+            .with_chip(Pca9544)
+            .with_address(multiplexer_addr);
+
+        {
+            let mut multiplexed_i2c_a = multiplexer.new_port(RefCellDevice::new(&i2c), 3);
+            let mut multiplexed_i2c_b = multiplexer.new_port(RefCellDevice::new(&i2c), 0);
+
+            let mut ma = [0; 1];
+            assert!(multiplexed_i2c_a.read(component_addr, &mut ma).is_ok());
+            assert_eq!(ma, [0x05]);
+
+            let mut mb = [0; 1];
+            assert!(multiplexed_i2c_b.read(component_addr, &mut mb).is_ok());
+            assert_eq!(mb, [0x06]);
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn two_channel_port() {
+        let multiplexer_addr = 0x01;
+        let component_addr = 0x02;
+
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![0b0000_0010]),
+            Transaction::write(component_addr, vec![0x05]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new()
+            .with_chip(Pca9543)
+            .with_address(multiplexer_addr);
+
+        {
+            assert!(matches!(
+                multiplexer.try_new_port(RefCellDevice::new(&i2c), 2),
+                Err(MultiplexerError::InvalidPort(2))
+            ));
+
+            let mut multiplexed_i2c = multiplexer
+                .try_new_port(RefCellDevice::new(&i2c), 1)
+                .unwrap();
+            assert!(multiplexed_i2c.write(component_addr, &[0x05]).is_ok());
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn fixed_address_mux_port() {
+        let multiplexer_addr = 0x70;
+        let component_addr = 0x02;
 
-            let mut md = [0; 2];
-            assert!(multiplexed_i2c_d.read(component_addr, &mut md).is_ok());
-            assert_eq!(md, [0x45, 0x48]);
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![0b0000_0101]),
+            Transaction::write(component_addr, vec![0x05]),
+            Transaction::write(multiplexer_addr, vec![0b0000_0100]),
+            Transaction::write(component_addr, vec![0x06]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new().with_chip(Pca9540);
+
+        {
+            assert!(multiplexer
+                .try_new_port(RefCellDevice::new(&i2c), 2)
+                .is_err());
+
+            let mut multiplexed_i2c_a = multiplexer
+                .try_new_port(RefCellDevice::new(&i2c), 1)
+                .unwrap();
+            let mut multiplexed_i2c_b = multiplexer
+                .try_new_port(RefCellDevice::new(&i2c), 0)
+                .unwrap();
+            assert!(multiplexed_i2c_a.write(component_addr, &[0x05]).is_ok());
+            assert!(multiplexed_i2c_b.write(component_addr, &[0x06]).is_ok());
         }
 
         i2c.into_inner().done();
     }
 
     #[test]
-    fn multi_port_read_write() {
+    fn eight_channel_mux_port() {
         let multiplexer_addr = 0x01;
         let component_addr = 0x02;
 
-        // Use port 1, 3, 2, 4 in that order
-        let ports = vec![
-            (0, 0b000_0001),
-            (2, 0b000_0100),
-            (1, 0b000_0010),
-            (3, 0b000_1000),
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![0b0000_1111]),
+            Transaction::write(component_addr, vec![0x05]),
+            Transaction::write(multiplexer_addr, vec![0b0000_1111]),
+            Transaction::read(component_addr, vec![0x06]),
         ];
 
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new()
+            .with_chip(Pca9547)
+            .with_address(multiplexer_addr);
+
+        {
+            let mut multiplexed_i2c = multiplexer.new_port(RefCellDevice::new(&i2c), 7);
+            assert!(multiplexed_i2c.write(component_addr, &[0x05]).is_ok());
+
+            let mut buffer = [0; 1];
+            assert!(multiplexed_i2c.read(component_addr, &mut buffer).is_ok());
+            assert_eq!(buffer, [0x06]);
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn mixed_chips() {
+        let component_addr = 0x02;
+
         let expectations = [
-            Transaction::write(multiplexer_addr, vec![ports[0].1]),
-            Transaction::write_read(component_addr, vec![0x05, 0x43], vec![0x33, 0x43]),
-            Transaction::write(multiplexer_addr, vec![ports[1].1]),
-            Transaction::write_read(component_addr, vec![0x55], vec![0x33, 0x43]),
-            Transaction::write(multiplexer_addr, vec![ports[2].1]),
-            Transaction::write_read(component_addr, vec![0x07, 0x39, 0x87], vec![0x33, 0x43]),
-            Transaction::write(multiplexer_addr, vec![ports[3].1]),
-            Transaction::write_read(component_addr, vec![0x45, 0x48], vec![0x33, 0x43]),
+            Transaction::write(0x70, vec![0b0000_0101]),
+            Transaction::write(component_addr, vec![0x05]),
+            Transaction::write(0x71, vec![0b0000_1000]),
+            Transaction::write(component_addr, vec![0x06]),
         ];
 
         let i2c = RefCell::new(Mock::new(&expectations));
-        let multiplexer = MultiplexerBus::new().with_address(multiplexer_addr);
+        let mux = MultiplexerBus::new().with_chip(Pca9542).with_address(0x70);
+        let switch = MultiplexerBus::new().with_chip(Pca9545).with_address(0x71);
 
         {
-            let mut multiplexed_i2c_a = multiplexer.new_port(RefCellDevice::new(&i2c), ports[0].0);
-            let mut multiplexed_i2c_b = multiplexer.new_port(RefCellDevice::new(&i2c), ports[1].0);
-            let mut multiplexed_i2c_c = multiplexer.new_port(RefCellDevice::new(&i2c), ports[2].0);
-            let mut multiplexed_i2c_d = multiplexer.new_port(RefCellDevice::new(&i2c), ports[3].0);
+            assert!(mux.try_new_port(RefCellDevice::new(&i2c), 2).is_err());
 
-            let mut ma = [0x33, 0x43];
-            assert!(multiplexed_i2c_a
-                .write_read(component_addr, &[0x05, 0x43], &mut ma)
-                .is_ok());
-            assert_eq!(ma, [0x33, 0x43]);
+            let mut multiplexed_i2c_a = mux.try_new_port(RefCellDevice::new(&i2c), 1).unwrap();
+            let mut multiplexed_i2c_b = switch.try_new_port(RefCellDevice::new(&i2c), 3).unwrap();
+            assert!(multiplexed_i2c_a.write(component_addr, &[0x05]).is_ok());
+            assert!(multiplexed_i2c_b.write(component_addr, &[0x06]).is_ok());
+        }
 
-            let mut mb = [0x33, 0x43];
-            assert!(multiplexed_i2c_b
-                .write_read(component_addr, &[0x55], &mut mb)
-                .is_ok());
-            assert_eq!(mb, [0x33, 0x43]);
+        i2c.into_inner().done();
+    }
 
-            let mut mc = [0x33, 0x43];
-            assert!(multiplexed_i2c_c
-                .write_read(component_addr, &[0x07, 0x39, 0x87], &mut mc)
-                .is_ok());
-            assert_eq!(mc, [0x33, 0x43]);
+    /// Active low switch, a cleared bit enables the port
+    struct InvertedEncoder;
 
-            let mut md = [0x33, 0x43];
-            assert!(multiplexed_i2c_d
-                .write_read(component_addr, &[0x45, 0x48], &mut md)
-                .is_ok());
-            assert_eq!(md, [0x33, 0x43]);
+    impl ChannelEncoder for InvertedEncoder {
+        fn select(port: u8) -> u8 {
+            !(1 << port) & 0b0000_1111
+        }
+
+        fn select_mask(mask: u8) -> Option<u8> {
+            Some(!mask & 0b0000_1111)
+        }
+
+        fn deselect() -> u8 {
+            0b0000_1111
+        }
+    }
+
+    struct InvertedSwitch;
+
+    impl Chip for InvertedSwitch {
+        type Ports<T> = [T; 4];
+        type Encoder = InvertedEncoder;
+    }
+
+    #[test]
+    fn custom_encoder() {
+        let multiplexer_addr = 0x01;
+        let component_addr = 0x02;
+
+        let expectations = [
+            Transaction::write(multiplexer_addr, vec![0b0000_1011]),
+            Transaction::write(component_addr, vec![0x05]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new()
+            .with_chip(InvertedSwitch)
+            .with_address(multiplexer_addr);
+
+        {
+            let mut multiplexed_i2c = multiplexer.new_port(RefCellDevice::new(&i2c), 2);
+            assert!(multiplexed_i2c.write(component_addr, &[0x05]).is_ok());
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[rstest]
+    #[case(None, 0b0000_0000)]
+    #[case(Some(0b0000_0011), 0b0000_0011)]
+    fn close_port(#[case] deselect: Option<u8>, #[case] result: u8) {
+        let expectations = [
+            Transaction::write(0x70, vec![0b0000_0101]),
+            Transaction::write(0x20, vec![0x05]),
+            Transaction::write(0x70, vec![result]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let mut multiplexer = MultiplexerBus::new().with_chip(Pca9544);
+        if let Some(deselect) = deselect {
+            multiplexer = multiplexer.with_deselect_byte(deselect);
+        }
+
+        {
+            let mut multiplexed_i2c = multiplexer.new_port(RefCellDevice::new(&i2c), 1);
+            assert!(multiplexed_i2c.write(0x20, &[0x05]).is_ok());
+            assert!(multiplexed_i2c.close_port().is_ok());
+        }
+
+        i2c.into_inner().done();
+    }
+
+    #[test]
+    fn control_register() {
+        let expectations = [
+            Transaction::write(0x70, vec![0x10, 0b0000_0100]),
+            Transaction::write(0x20, vec![0x05]),
+            Transaction::write(0x70, vec![0x10, 0b0000_0000]),
+        ];
+
+        let i2c = RefCell::new(Mock::new(&expectations));
+        let multiplexer = MultiplexerBus::new().with_control_register(0x10);
+
+        {
+            let mut multiplexed_i2c = multiplexer.new_port(RefCellDevice::new(&i2c), 2);
+            assert!(multiplexed_i2c.write(0x20, &[0x05]).is_ok());
+            assert!(multiplexed_i2c.close_port().is_ok());
         }
 
         i2c.into_inner().done();
     }
+
+    /// Records ten bit transfers on the mock the way they go out on the wire, the address
+    /// prefix `0b11110xx` followed by the low address byte
+    struct TenBitBus(Mock);
+
+    impl embedded_hal::i2c::ErrorType for TenBitBus {
+        type Error = embedded_hal::i2c::ErrorKind;
+    }
+
+    impl I2c for TenBitBus {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [embedded_hal::i2c::Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            self.0.transaction(address, operations)
+        }
+
+        fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+            self.0.write(address, write)
+        }
+    }
+
+    impl I2c<embedded_hal::i2c::TenBitAddress> for TenBitBus {
+        fn transaction(
+            &mut self,
+            address: u16,
+            operations: &mut [embedded_hal::i2c::Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            let prefix = 0b0111_1000 | (address >> 8) as u8;
+            for operation in operations {
+                match operation {
+                    embedded_hal::i2c::Operation::Write(write) => {
+                        let mut bytes = vec![address as u8];
+                        bytes.extend_from_slice(write);
+                        self.0.write(prefix, &bytes)?
+                    }
+                    embedded_hal::i2c::Operation::Read(read) => {
+                        self.0.write_read(prefix, &[address as u8], read)?
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ten_bit_address() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x7A, vec![0xA5, 0x05]),
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write_read(0x7A, vec![0xA5], vec![0x06]),
+        ]);
+        let multiplexer = MultiplexerBus::new();
+
+        {
+            let mut multiplexed_i2c = multiplexer.new_port(TenBitBus(i2c.clone()), 2);
+            assert!(I2c::<u16>::write(&mut multiplexed_i2c, 0x2A5, &[0x05]).is_ok());
+            let mut read = [0];
+            assert!(I2c::<u16>::read(&mut multiplexed_i2c, 0x2A5, &mut read).is_ok());
+            assert_eq!(read, [0x06]);
+        }
+
+        i2c.done();
+    }
+
+    #[cfg(feature = "async")]
+    mod asynch {
+        extern crate alloc;
+        use crate::prelude::*;
+        use alloc::vec;
+        use alloc::vec::Vec;
+        use embassy_futures::block_on;
+        use embedded_hal::i2c::ErrorKind;
+        use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+        use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+        use rstest::*;
+
+        #[test]
+        fn write() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0100]),
+                Transaction::write(0x20, vec![0x05]),
+                Transaction::write(0x70, vec![0b0000_0100]),
+                Transaction::write_read(0x20, vec![0x01], vec![0x02]),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let mut multiplexed_i2c = multiplexer.new_port(&mut i2c, 2);
+                assert!(block_on(multiplexed_i2c.write(0x20, &[0x05])).is_ok());
+                let mut read = [0];
+                assert!(block_on(multiplexed_i2c.write_read(0x20, &[0x01], &mut read)).is_ok());
+                assert_eq!(read, [0x02]);
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn select_error() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0001]).with_error(ErrorKind::Other)
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let mut multiplexed_i2c = multiplexer.new_port(&mut i2c, 0);
+                assert_eq!(
+                    block_on(multiplexed_i2c.read(0x20, &mut [0])),
+                    Err(MultiplexerError::Select(ErrorKind::Other))
+                );
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn empty_transaction() {
+            let mut i2c = Mock::new(&[]);
+            let multiplexer = MultiplexerBus::new().with_cache();
+            assert!(block_on(
+                multiplexer
+                    .bus()
+                    .new_port(&mut i2c, 1)
+                    .transaction(0x20, &mut [])
+            )
+            .is_ok());
+            assert!(block_on(
+                multiplexer
+                    .new_cached_port(&mut i2c, 1)
+                    .transaction(0x20, &mut [])
+            )
+            .is_ok());
+            i2c.done();
+        }
+
+        #[test]
+        fn pre_select() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::write(0x20, vec![0x01]),
+                Transaction::write(0x20, vec![0x02]),
+            ]);
+            let mut port = MultiplexerBus::new()
+                .new_port(&mut i2c, 1)
+                .with_pre_select(false);
+            assert!(block_on(port.select_async()).is_ok());
+            assert!(block_on(port.write(0x20, &[0x01])).is_ok());
+            assert!(block_on(port.write(0x20, &[0x02])).is_ok());
+            i2c.done();
+        }
+
+        #[test]
+        fn verify_before_operation() {
+            let mut i2c = Mock::new(&[
+                Transaction::write_read(0x70, vec![0x00], vec![0b0000_0010]),
+                Transaction::write(0x20, vec![0x01]),
+                Transaction::write_read(0x70, vec![0x00], vec![0b0000_0001]),
+                Transaction::write(0x70, vec![0x00, 0b0000_0010]),
+                Transaction::write(0x20, vec![0x02]),
+            ]);
+            let mut port = MultiplexerBus::new()
+                .with_control_register(0x00)
+                .new_port(&mut i2c, 1)
+                .with_verify_before_operation(true);
+            assert!(block_on(port.write(0x20, &[0x01])).is_ok());
+            assert!(block_on(port.write(0x20, &[0x02])).is_ok());
+            assert_eq!(port.external_changes(), 1);
+            i2c.done();
+        }
+
+        /// Bus implementing only the async trait
+        struct AsyncOnly(Mock);
+
+        impl embedded_hal_async::i2c::ErrorType for AsyncOnly {
+            type Error = ErrorKind;
+        }
+
+        impl I2c for AsyncOnly {
+            async fn transaction(
+                &mut self,
+                address: u8,
+                operations: &mut [embedded_hal::i2c::Operation<'_>],
+            ) -> Result<(), Self::Error> {
+                I2c::transaction(&mut self.0, address, operations).await
+            }
+        }
+
+        #[test]
+        fn try_new_port() {
+            let mut i2c = Mock::new(&[
+                Transaction::transaction_start(0x70),
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::transaction_end(0x70),
+                Transaction::transaction_start(0x20),
+                Transaction::write(0x20, vec![0x05]),
+                Transaction::transaction_end(0x20),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+            assert_eq!(
+                multiplexer
+                    .try_new_port(AsyncOnly(i2c.clone()), 4)
+                    .map(|_| ()),
+                Err(MultiplexerError::InvalidPort(4))
+            );
+            let mut port = multiplexer.try_new_port(AsyncOnly(i2c.clone()), 1).unwrap();
+            assert!(block_on(port.write(0x20, &[0x05])).is_ok());
+            i2c.done();
+        }
+
+        #[test]
+        fn new_group() {
+            let mut i2c = Mock::new(&[
+                Transaction::transaction_start(0x70),
+                Transaction::write(0x70, vec![0b0000_0101]),
+                Transaction::transaction_end(0x70),
+                Transaction::transaction_start(0x20),
+                Transaction::write(0x20, vec![0x05]),
+                Transaction::transaction_end(0x20),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+            assert_eq!(
+                multiplexer.new_group(AsyncOnly(i2c.clone()), 0).map(|_| ()),
+                Err(MultiplexerError::UnsupportedPorts(0))
+            );
+            let mut port = multiplexer
+                .new_group(AsyncOnly(i2c.clone()), 0b0000_0101)
+                .unwrap();
+            assert!(block_on(port.write(0x20, &[0x05])).is_ok());
+            assert_eq!(
+                block_on(port.read(0x20, &mut [0])),
+                Err(MultiplexerError::BroadcastReadUnsupported)
+            );
+            i2c.done();
+        }
+
+        #[test]
+        fn cached_port() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0100]),
+                Transaction::write(0x20, vec![0x05]),
+                Transaction::write_read(0x20, vec![0x01], vec![0x02]),
+            ]);
+            let multiplexer = MultiplexerBus::new().with_cache();
+
+            {
+                let mut multiplexed_i2c = multiplexer.new_cached_port(&mut i2c, 2);
+                assert!(block_on(multiplexed_i2c.write(0x20, &[0x05])).is_ok());
+                let mut read = [0];
+                assert!(block_on(multiplexed_i2c.write_read(0x20, &[0x01], &mut read)).is_ok());
+                assert_eq!(read, [0x02]);
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn idle_disconnect() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0001]),
+                Transaction::read(0x20, vec![0]).with_error(ErrorKind::Other),
+                Transaction::write(0x70, vec![0b0000_0000]),
+            ]);
+            let multiplexer = MultiplexerBus::new().with_idle_disconnect(true);
+
+            {
+                let mut multiplexed_i2c = multiplexer.new_port(&mut i2c, 0);
+                assert_eq!(
+                    block_on(multiplexed_i2c.read(0x20, &mut [0])),
+                    Err(MultiplexerError::Transfer(ErrorKind::Other))
+                );
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn transfer_error() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0001]),
+                Transaction::read(0x20, vec![0]).with_error(ErrorKind::Other),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let mut multiplexed_i2c = multiplexer.new_port(&mut i2c, 0);
+                assert_eq!(
+                    block_on(multiplexed_i2c.read(0x20, &mut [0])),
+                    Err(MultiplexerError::Transfer(ErrorKind::Other))
+                );
+            }
+
+            i2c.done();
+        }
+
+        /// Records the delay as a write to address 0x00 on the shared mock, so the expectations
+        /// show where it happened
+        struct MarkerDelay(Mock);
+
+        impl DelayNs for MarkerDelay {
+            async fn delay_ns(&mut self, ns: u32) {
+                self.0.write(0x00, &ns.to_be_bytes()).await.unwrap();
+            }
+        }
+
+        #[rstest]
+        #[case(50_000, vec![Transaction::write(0x00, 50_000u32.to_be_bytes().to_vec())])]
+        #[case(0, vec![])]
+        fn settle_delay(#[case] ns: u32, #[case] delay: Vec<Transaction>) {
+            let mut expectations = vec![Transaction::write(0x70, vec![0b0000_0010])];
+            expectations.extend(delay);
+            expectations.push(Transaction::write(0x20, vec![0x05]));
+            let mut i2c = Mock::new(&expectations);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let delay = MarkerDelay(i2c.clone());
+                let mut multiplexed_i2c = multiplexer
+                    .new_port(&mut i2c, 1)
+                    .with_settle_delay(ns, delay);
+                assert!(block_on(multiplexed_i2c.write(0x20, &[0x05])).is_ok());
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn settle_delay_skipped_on_select_error() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Other)
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let delay = MarkerDelay(i2c.clone());
+                let mut multiplexed_i2c = multiplexer
+                    .new_port(&mut i2c, 1)
+                    .with_settle_delay(50_000, delay);
+                assert_eq!(
+                    block_on(multiplexed_i2c.write(0x20, &[0x05])),
+                    Err(MultiplexerError::Select(ErrorKind::Other))
+                );
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn select_retries() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::NoAcknowledge(
+                    embedded_hal::i2c::NoAcknowledgeSource::Address,
+                )),
+                Transaction::write(0x00, 100_000u32.to_be_bytes().to_vec()),
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::write(0x20, vec![0x05]),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let delay = MarkerDelay(i2c.clone());
+                let mut multiplexed_i2c = multiplexer
+                    .new_port(&mut i2c, 1)
+                    .with_delay(delay)
+                    .with_select_retries(2, 100);
+                assert!(block_on(multiplexed_i2c.write(0x20, &[0x05])).is_ok());
+                assert_eq!(multiplexed_i2c.retries(), 1);
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn select_retries_exhausted() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Other),
+                Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Other),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let mut multiplexed_i2c =
+                    multiplexer.new_port(&mut i2c, 1).with_select_retries(1, 0);
+                assert_eq!(
+                    block_on(multiplexed_i2c.write(0x20, &[0x05])),
+                    Err(MultiplexerError::SelectFailed {
+                        attempts: 2,
+                        error: ErrorKind::Other
+                    })
+                );
+                assert_eq!(multiplexed_i2c.retries(), 1);
+            }
+
+            i2c.done();
+        }
+
+        #[cfg(feature = "embassy")]
+        #[rstest]
+        #[case(false, vec![])]
+        #[case(true, vec![Transaction::write(0x70, vec![0b0000_0000])])]
+        fn select_path(#[case] deselect: bool, #[case] after: Vec<Transaction>) {
+            use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+
+            let mut expectations = vec![
+                Transaction::write(0x70, vec![0b0000_0100]),
+                Transaction::write(0x71, vec![0b0000_0001]),
+                Transaction::write(0x20, vec![0x05]),
+            ];
+            expectations.extend(after);
+            let bus = Mutex::<NoopRawMutex, _>::new(Mock::new(&expectations));
+
+            {
+                let mut leaf = crate::bus::select_path(&bus, [(0x70, 2), (0x71, 0)])
+                    .with_parent_deselect(deselect);
+                assert!(block_on(leaf.write(0x20, &[0x05])).is_ok());
+            }
+
+            bus.into_inner().done();
+        }
+
+        #[cfg(feature = "embassy")]
+        #[test]
+        fn select_path_mux_leaf() {
+            use crate::bus::PathPort;
+            use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+
+            let bus = Mutex::<NoopRawMutex, _>::new(Mock::new(&[
+                Transaction::write(0x70, vec![0b1000_0000]),
+                Transaction::write(0x74, vec![0b0000_0101]),
+                Transaction::write(0x20, vec![0x05]).with_error(ErrorKind::Other),
+                Transaction::write(0x70, vec![0b0000_0000]),
+            ]));
+
+            {
+                let root = MultiplexerBus::new().with_chip(Tca9548).new_port((), 7);
+                let leaf = MultiplexerBus::new()
+                    .with_chip(Pca9544)
+                    .with_address(0x74)
+                    .new_port((), 1);
+                let mut port = PathPort::new(&bus, [root, leaf]).with_parent_deselect(true);
+                // The parent is still deselected when the transfer fails
+                assert_eq!(
+                    block_on(port.write(0x20, &[0x05])),
+                    Err(MultiplexerError::Transfer(ErrorKind::Other))
+                );
+            }
+
+            bus.into_inner().done();
+        }
+
+        #[test]
+        fn verified_select_retry() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::read(0x70, vec![0b0000_0011]),
+                Transaction::write(0x70, vec![0b0000_0010]),
+                // Interrupt bits don't count as a mismatch
+                Transaction::read(0x70, vec![0b0100_0010]),
+                Transaction::write(0x00, 50_000u32.to_be_bytes().to_vec()),
+                Transaction::write(0x20, vec![0x05]),
+            ]);
+            let multiplexer = MultiplexerBus::new().with_chip(Pca9545);
+
+            {
+                let delay = MarkerDelay(i2c.clone());
+                let mut multiplexed_i2c = multiplexer
+                    .new_port(&mut i2c, 1)
+                    .with_settle_delay(50_000, delay)
+                    .with_verified_select(true);
+                assert!(block_on(multiplexed_i2c.write(0x20, &[0x05])).is_ok());
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn verified_select_mismatch() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x74, vec![0x03, 0b0000_0101]),
+                Transaction::write_read(0x74, vec![0x03], vec![0b0000_0100]),
+                Transaction::write(0x74, vec![0x03, 0b0000_0101]),
+                Transaction::write_read(0x74, vec![0x03], vec![0b0000_0100]),
+            ]);
+            let multiplexer = MultiplexerBus::new()
+                .with_chip(Pca9544)
+                .with_address(0x74)
+                .with_control_register(0x03);
+
+            {
+                let mut multiplexed_i2c =
+                    multiplexer.new_port(&mut i2c, 1).with_verified_select(true);
+                assert_eq!(
+                    block_on(multiplexed_i2c.write(0x20, &[0x05])),
+                    Err(MultiplexerError::SelectVerificationFailed {
+                        expected: 0b0000_0101,
+                        actual: 0b0000_0100,
+                    })
+                );
+            }
+
+            i2c.done();
+        }
+
+        /// Passes everything to the mock except a read of 0x20, which never completes the first
+        /// time, like a device stretching the clock forever
+        #[cfg(feature = "embassy")]
+        struct HangingBus {
+            mock: Mock,
+            hang: bool,
+        }
+
+        #[cfg(feature = "embassy")]
+        impl embedded_hal::i2c::ErrorType for HangingBus {
+            type Error = ErrorKind;
+        }
+
+        #[cfg(feature = "embassy")]
+        impl I2c for HangingBus {
+            async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+                if address == 0x20 && core::mem::take(&mut self.hang) {
+                    core::future::pending::<()>().await;
+                }
+                self.mock.read(address, read).await
+            }
+
+            async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+                self.mock.write(address, write).await
+            }
+
+            async fn transaction(
+                &mut self,
+                address: u8,
+                operations: &mut [embedded_hal::i2c::Operation<'_>],
+            ) -> Result<(), Self::Error> {
+                self.mock.transaction(address, operations).await
+            }
+        }
+
+        #[cfg(feature = "embassy")]
+        #[test]
+        fn timeout() {
+            use crate::bus::{NoDelay, TimeoutPort};
+
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0001]),
+                Transaction::write(0x70, vec![0b0000_0001]),
+                Transaction::read(0x20, vec![0x06]),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let bus = HangingBus {
+                    mock: i2c.clone(),
+                    hang: true,
+                };
+                let mut port = TimeoutPort::new(multiplexer.new_port(bus, 0), NoDelay, 1_000);
+                let mut read = [0];
+                assert_eq!(
+                    block_on(port.read(0x20, &mut read)),
+                    Err(MultiplexerError::Timeout)
+                );
+                assert!(block_on(port.read(0x20, &mut read)).is_ok());
+                assert_eq!(read, [0x06]);
+            }
+
+            i2c.done();
+        }
+
+        #[cfg(feature = "embassy")]
+        #[test]
+        fn split_async() {
+            use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+
+            let i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_1000]),
+                Transaction::write(0x20, vec![0x05]),
+                Transaction::write(0x70, vec![0b0000_0001]),
+                Transaction::read(0x21, vec![0x06]),
+            ]);
+            let bus = Mutex::<NoopRawMutex, _>::new(i2c);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let [mut port0, _, _, mut port3] = multiplexer.split_async(&bus);
+                assert!(block_on(port3.write(0x20, &[0x05])).is_ok());
+                let mut read = [0];
+                assert!(block_on(port0.read(0x21, &mut read)).is_ok());
+                assert_eq!(read, [0x06]);
+            }
+
+            bus.into_inner().done();
+        }
+
+        /// Yields inside every transfer so other tasks run while the bus is locked
+        #[cfg(feature = "embassy")]
+        struct YieldingBus(Mock);
+
+        #[cfg(feature = "embassy")]
+        impl embedded_hal::i2c::ErrorType for YieldingBus {
+            type Error = ErrorKind;
+        }
+
+        #[cfg(feature = "embassy")]
+        impl I2c for YieldingBus {
+            async fn transaction(
+                &mut self,
+                address: u8,
+                operations: &mut [embedded_hal::i2c::Operation<'_>],
+            ) -> Result<(), Self::Error> {
+                embassy_futures::yield_now().await;
+                self.0.transaction(address, operations).await
+            }
+
+            async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+                embassy_futures::yield_now().await;
+                self.0.write(address, write).await
+            }
+        }
+
+        #[cfg(feature = "embassy")]
+        #[test]
+        fn atomic_ports() {
+            use embassy_futures::join::join;
+            use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+
+            let i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0001]),
+                Transaction::write(0x20, vec![0x01]),
+                Transaction::write(0x70, vec![0b0000_1000]),
+                Transaction::write(0x21, vec![0x02]),
+            ]);
+            let bus = Mutex::<NoopRawMutex, _>::new(YieldingBus(i2c));
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let [mut port0, _, _, mut port3] = multiplexer.split_atomic(&bus);
+                // The second task tries to switch to port 3 while the first holds the bus
+                let (first, second) =
+                    block_on(join(port0.write(0x20, &[0x01]), port3.write(0x21, &[0x02])));
+                assert!(first.is_ok());
+                assert!(second.is_ok());
+            }
+
+            bus.into_inner().0.done();
+        }
+    }
+
+    #[cfg(feature = "eh02")]
+    mod eh02 {
+        extern crate alloc;
+        extern crate std;
+        use crate::error::Eh02Error;
+        use crate::prelude::*;
+        use alloc::vec;
+        use embedded_hal_02::blocking::i2c::{Read, Write, WriteRead};
+        use embedded_hal_mock::eh0::i2c::{Mock, Transaction};
+        use embedded_hal_mock::eh0::MockError;
+        use embedded_hal_mock::eh1::delay::{CheckedDelay, Transaction as DelayTransaction};
+
+        /// Driver written against the 0.2 traits
+        fn read_register<I2C: Write + WriteRead>(
+            i2c: &mut I2C,
+            register: u8,
+        ) -> Result<u8, <I2C as WriteRead>::Error> {
+            let mut read = [0];
+            i2c.write_read(0x20, &[register], &mut read)?;
+            Ok(read[0])
+        }
+
+        #[test]
+        fn legacy_driver() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::write_read(0x20, vec![0x01], vec![0x42]),
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::write(0x20, vec![0x05]),
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::read(0x20, vec![0x06]),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let mut multiplexed_i2c = multiplexer.new_port(i2c.clone(), 1);
+                assert_eq!(read_register(&mut multiplexed_i2c, 0x01), Ok(0x42));
+                assert!(multiplexed_i2c.write(0x20, &[0x05]).is_ok());
+                let mut read = [0];
+                assert!(multiplexed_i2c.read(0x20, &mut read).is_ok());
+                assert_eq!(read, [0x06]);
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn legacy_errors() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0001])
+                    .with_error(MockError::Io(std::io::ErrorKind::Other)),
+                Transaction::write(0x70, vec![0b0000_0001]),
+                Transaction::write(0x20, vec![0x05])
+                    .with_error(MockError::Io(std::io::ErrorKind::Other)),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let mut multiplexed_i2c = multiplexer.new_port(i2c.clone(), 0);
+                assert!(matches!(
+                    multiplexed_i2c.write(0x20, &[0x05]),
+                    Err(MultiplexerError::Select(Eh02Error(MockError::Io(_))))
+                ));
+                assert!(matches!(
+                    multiplexed_i2c.write(0x20, &[0x05]),
+                    Err(MultiplexerError::Transfer(Eh02Error(MockError::Io(_))))
+                ));
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn select_retries() {
+            let error = || MockError::Io(std::io::ErrorKind::Other);
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0010]).with_error(error()),
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::write(0x20, vec![0x05]),
+                Transaction::write(0x70, vec![0b0000_0010]).with_error(error()),
+                Transaction::write(0x70, vec![0b0000_0010]).with_error(error()),
+            ]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let mut multiplexed_i2c = multiplexer
+                    .new_port(i2c.clone(), 1)
+                    .with_select_retries(1, 0);
+                assert!(multiplexed_i2c.write(0x20, &[0x05]).is_ok());
+                assert_eq!(multiplexed_i2c.retries(), 1);
+                assert!(matches!(
+                    multiplexed_i2c.write(0x20, &[0x05]),
+                    Err(MultiplexerError::SelectFailed {
+                        attempts: 2,
+                        error: Eh02Error(MockError::Io(_))
+                    })
+                ));
+            }
+
+            i2c.done();
+        }
+
+        #[test]
+        fn settle_delay() {
+            let mut i2c = Mock::new(&[
+                Transaction::write(0x70, vec![0b0000_0010]),
+                Transaction::write(0x20, vec![0x05]),
+            ]);
+            let mut delay = CheckedDelay::new(&[DelayTransaction::delay_ns(50_000)]);
+            let multiplexer = MultiplexerBus::new();
+
+            {
+                let mut multiplexed_i2c = multiplexer
+                    .new_port(i2c.clone(), 1)
+                    .with_settle_delay(50_000, &mut delay);
+                assert!(multiplexed_i2c.write(0x20, &[0x05]).is_ok());
+            }
+
+            delay.done();
+            i2c.done();
+        }
+    }
 }
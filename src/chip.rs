@@ -0,0 +1,450 @@
+/// Fixed size array holding one value per channel
+pub trait PortArray<T>: AsRef<[T]> + AsMut<[T]> {
+    /// Number of channels
+    const LEN: usize;
+
+    /// Creates the array by calling `f` with each channel index
+    fn from_fn(f: impl FnMut(usize) -> T) -> Self;
+}
+
+impl<T, const N: usize> PortArray<T> for [T; N] {
+    const LEN: usize = N;
+
+    fn from_fn(f: impl FnMut(usize) -> T) -> Self {
+        core::array::from_fn(f)
+    }
+}
+
+/// Mask with every port of the chip enabled
+#[cfg(any(feature = "sync", feature = "async", feature = "bus"))]
+pub(crate) fn all_ports<C: Chip>() -> u8 {
+    ((1u16 << C::CHANNELS) - 1) as u8
+}
+
+/// Translates port selections into control register values
+pub trait ChannelEncoder {
+    /// Control byte enabling only the given port
+    fn select(port: u8) -> u8;
+
+    /// Control byte enabling every port in the mask, `None` if the chip can't enable that
+    /// combination of ports
+    fn select_mask(mask: u8) -> Option<u8>;
+
+    /// Control byte disabling every port
+    fn deselect() -> u8;
+
+    /// Mask of the ports enabled by a control byte read back from the chip, one bit per channel
+    /// unless overridden
+    fn decode(code: u8) -> u8 {
+        code
+    }
+}
+
+/// One bit per channel, any combination of channels can be enabled (switches)
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OneHot;
+
+impl ChannelEncoder for OneHot {
+    fn select(port: u8) -> u8 {
+        1 << port
+    }
+
+    fn select_mask(mask: u8) -> Option<u8> {
+        Some(mask)
+    }
+
+    fn deselect() -> u8 {
+        0
+    }
+}
+
+/// An enable bit plus the channel number, only one channel can be enabled (muxes)
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EnableChannel<const ENABLE: u8>;
+
+impl<const ENABLE: u8> ChannelEncoder for EnableChannel<ENABLE> {
+    fn select(port: u8) -> u8 {
+        ENABLE | port
+    }
+
+    fn select_mask(mask: u8) -> Option<u8> {
+        match mask.count_ones() {
+            0 => Some(Self::deselect()),
+            1 => Some(Self::select(mask.trailing_zeros() as u8)),
+            _ => None,
+        }
+    }
+
+    fn deselect() -> u8 {
+        0
+    }
+
+    fn decode(code: u8) -> u8 {
+        match code & ENABLE {
+            0 => 0,
+            _ => 1 << (code & (ENABLE - 1)),
+        }
+    }
+}
+
+/// Describes a supported multiplexer part
+pub trait Chip {
+    /// One value per downstream channel, e.g. `[T; 4]` for a four channel part
+    type Ports<T>: PortArray<T>;
+
+    /// Number of downstream channels
+    const CHANNELS: u8 = <Self::Ports<()> as PortArray<()>>::LEN as u8;
+
+    /// Control register format
+    type Encoder: ChannelEncoder;
+
+    /// Mask of the ports enabled at power-up and after a reset
+    const POWER_ON_PORTS: u8 = 0;
+}
+
+/// Switches, any combination of channels can be enabled at the same time
+pub trait Switch: Chip {}
+
+/// Muxes, at most one channel is enabled at a time
+pub trait Mux: Chip {}
+
+/// Chips reporting their interrupt inputs in the upper nibble of the control register
+///
+/// ```
+/// # use i2c_multiplexer::prelude::*;
+/// # #[cfg(feature = "sync")]
+/// # fn read<I2C: embedded_hal::i2c::I2c>(i2c: I2C) {
+/// let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9545);
+/// let interrupts = multiplexer.interrupts();
+/// # }
+/// ```
+///
+/// The PCA9546A has no interrupt inputs so there is nothing to read
+///
+/// ```compile_fail
+/// # use embedded_hal::i2c::I2c;
+/// # use i2c_multiplexer::prelude::*;
+/// # fn read<I2C: I2c>(i2c: I2C) {
+/// let mut multiplexer = Multiplexer::new(i2c).with_chip(Pca9546);
+/// let interrupts = multiplexer.interrupts();
+/// # }
+/// ```
+pub trait Interrupts: Chip {}
+
+/// Chips answering the I2C Device ID sequence
+pub trait Identify: Chip {}
+
+/// Contents of the I2C Device ID
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub struct DeviceId {
+    /// 12 bit manufacturer code, NXP is `0x000`
+    pub manufacturer: u16,
+    /// 9 bit part identification
+    pub part: u16,
+    /// 3 bit die revision
+    pub revision: u8,
+}
+
+impl From<[u8; 3]> for DeviceId {
+    fn from(bytes: [u8; 3]) -> Self {
+        let raw = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        Self {
+            manufacturer: (raw >> 12) as u16,
+            part: ((raw >> 3) & 0x1FF) as u16,
+            revision: (raw & 0b111) as u8,
+        }
+    }
+}
+
+/// MAX735x chips with a configuration register next to the switch control register
+pub trait EnhancedMode: Chip {}
+
+/// MAX7358 style chips which can disconnect and flush out a locked up channel
+pub trait LockupRecovery: EnhancedMode {}
+
+/// Control register readback of the PCA9544A / TCA9544A
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Tca9544Status {
+    /// Currently selected port, `None` if the enable bit is cleared
+    pub selected: Option<u8>,
+    /// Ports with their interrupt input asserted
+    pub interrupts: [bool; 4],
+}
+
+impl Tca9544Status {
+    /// Lowest numbered port with its interrupt input asserted
+    pub fn next_interrupting_port(&self) -> Option<u8> {
+        self.interrupts
+            .iter()
+            .position(|interrupt| *interrupt)
+            .map(|port| port as u8)
+    }
+}
+
+impl From<u8> for Tca9544Status {
+    fn from(code: u8) -> Self {
+        Self {
+            selected: (code & 0b0000_0100 != 0).then_some(code & 0b0000_0011),
+            interrupts: core::array::from_fn(|port| code & (1 << (port + 4)) != 0),
+        }
+    }
+}
+
+/// Chips with hardware address pins, the PCA9540B has a fixed address
+pub trait AddressPins: Chip {}
+
+/// Chips with an active low RESET pin which disables all channels
+pub trait Reset: Chip {
+    /// Minimum time RESET has to be held low
+    const RESET_PULSE_NS: u32 = 6;
+    /// Time after RESET is released until the chip can be addressed again
+    const RESET_RECOVERY_NS: u32 = 500;
+}
+
+/// PCA9546A / TCA9546A four channel switch, has no interrupt inputs
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9546;
+
+impl Chip for Pca9546 {
+    type Ports<T> = [T; 4];
+    type Encoder = OneHot;
+}
+
+impl Switch for Pca9546 {}
+
+impl Reset for Pca9546 {}
+
+impl AddressPins for Pca9546 {}
+
+/// PCA9545A four channel switch with interrupts
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9545;
+
+impl Chip for Pca9545 {
+    type Ports<T> = [T; 4];
+    type Encoder = OneHot;
+}
+
+impl Switch for Pca9545 {}
+
+impl Interrupts for Pca9545 {}
+
+impl Reset for Pca9545 {}
+
+impl AddressPins for Pca9545 {}
+
+/// PCA9543A two channel switch with interrupts
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9543;
+
+impl Chip for Pca9543 {
+    type Ports<T> = [T; 2];
+    type Encoder = OneHot;
+}
+
+impl Switch for Pca9543 {}
+
+impl Interrupts for Pca9543 {}
+
+impl Reset for Pca9543 {}
+
+impl AddressPins for Pca9543 {}
+
+/// PCA9544A / TCA9544A four channel mux with interrupts
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9544;
+
+impl Chip for Pca9544 {
+    type Ports<T> = [T; 4];
+    type Encoder = EnableChannel<0b0000_0100>;
+}
+
+impl Mux for Pca9544 {}
+
+impl Interrupts for Pca9544 {}
+
+impl Reset for Pca9544 {}
+
+impl AddressPins for Pca9544 {}
+
+/// PCA9540B two channel mux with a fixed address and no reset
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9540;
+
+impl Chip for Pca9540 {
+    type Ports<T> = [T; 2];
+    type Encoder = EnableChannel<0b0000_0100>;
+}
+
+impl Mux for Pca9540 {}
+
+/// PCA9542A two channel mux with interrupts and no reset
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9542;
+
+impl Chip for Pca9542 {
+    type Ports<T> = [T; 2];
+    type Encoder = EnableChannel<0b0000_0100>;
+}
+
+impl Mux for Pca9542 {}
+
+impl Interrupts for Pca9542 {}
+
+impl AddressPins for Pca9542 {}
+
+/// PCA9846 four channel switch with Device ID
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9846;
+
+impl Chip for Pca9846 {
+    type Ports<T> = [T; 4];
+    type Encoder = OneHot;
+}
+
+impl Switch for Pca9846 {}
+
+impl Identify for Pca9846 {}
+
+impl Reset for Pca9846 {}
+
+impl AddressPins for Pca9846 {}
+
+/// PCA9849 four channel mux with Device ID
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9849;
+
+impl Chip for Pca9849 {
+    type Ports<T> = [T; 4];
+    type Encoder = EnableChannel<0b0000_0100>;
+}
+
+impl Mux for Pca9849 {}
+
+impl Identify for Pca9849 {}
+
+impl Reset for Pca9849 {}
+
+impl AddressPins for Pca9849 {}
+
+/// PCA9547 eight channel mux, port 0 is enabled at power-up
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pca9547;
+
+impl Chip for Pca9547 {
+    type Ports<T> = [T; 8];
+    type Encoder = EnableChannel<0b0000_1000>;
+    const POWER_ON_PORTS: u8 = 0b0000_0001;
+}
+
+impl Mux for Pca9547 {}
+
+impl Reset for Pca9547 {}
+
+impl AddressPins for Pca9547 {}
+
+/// TCA9548A / PCA9548A eight channel switch
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Tca9548;
+
+impl Chip for Tca9548 {
+    type Ports<T> = [T; 8];
+    type Encoder = OneHot;
+}
+
+impl Switch for Tca9548 {}
+
+impl Reset for Tca9548 {}
+
+impl AddressPins for Tca9548 {}
+
+/// MAX7357 eight channel switch with bus lock-up detection, behaves like a TCA9548A until
+/// enhanced mode is enabled
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Max7357;
+
+impl Chip for Max7357 {
+    type Ports<T> = [T; 8];
+    type Encoder = OneHot;
+}
+
+impl Switch for Max7357 {}
+
+impl EnhancedMode for Max7357 {}
+
+impl Reset for Max7357 {}
+
+impl AddressPins for Max7357 {}
+
+/// MAX7358 eight channel switch which can disconnect and flush out locked up channels
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Max7358;
+
+impl Chip for Max7358 {
+    type Ports<T> = [T; 8];
+    type Encoder = OneHot;
+}
+
+impl Switch for Max7358 {}
+
+impl EnhancedMode for Max7358 {}
+
+impl LockupRecovery for Max7358 {}
+
+impl Reset for Max7358 {}
+
+impl AddressPins for Max7358 {}
+
+#[cfg(test)]
+mod test {
+    use crate::chip::{ChannelEncoder, EnableChannel, OneHot};
+    use rstest::*;
+
+    #[rstest]
+    #[case(0, 0b0000_0001)]
+    #[case(3, 0b0000_1000)]
+    #[case(7, 0b1000_0000)]
+    fn one_hot_select(#[case] port: u8, #[case] result: u8) {
+        assert_eq!(OneHot::select(port), result);
+    }
+
+    #[rstest]
+    #[case(0b0000_0000)]
+    #[case(0b0000_0101)]
+    #[case(0b1111_1111)]
+    fn one_hot_select_mask(#[case] mask: u8) {
+        assert_eq!(OneHot::select_mask(mask), Some(mask));
+    }
+
+    #[rstest]
+    #[case(0, 0b0000_0100)]
+    #[case(3, 0b0000_0111)]
+    fn enable_channel_select(#[case] port: u8, #[case] result: u8) {
+        assert_eq!(EnableChannel::<0b0000_0100>::select(port), result);
+    }
+
+    #[rstest]
+    #[case(0b0000_0000, Some(0b0000_0000))]
+    #[case(0b0000_0001, Some(0b0000_1000))]
+    #[case(0b1000_0000, Some(0b0000_1111))]
+    #[case(0b0000_0101, None)]
+    fn enable_channel_select_mask(#[case] mask: u8, #[case] result: Option<u8>) {
+        assert_eq!(EnableChannel::<0b0000_1000>::select_mask(mask), result);
+    }
+
+    #[rstest]
+    #[case(0b0000_0000, 0b0000_0000)]
+    #[case(0b0000_0110, 0b0000_0100)]
+    // Channel bits and interrupt inputs without the enable bit select nothing
+    #[case(0b0101_0011, 0b0000_0000)]
+    #[case(0b1100_0101, 0b0000_0010)]
+    fn enable_channel_decode(#[case] code: u8, #[case] result: u8) {
+        assert_eq!(EnableChannel::<0b0000_0100>::decode(code), result);
+    }
+
+    #[test]
+    fn deselect() {
+        assert_eq!(OneHot::deselect(), 0);
+        assert_eq!(EnableChannel::<0b0000_0100>::deselect(), 0);
+    }
+}
@@ -0,0 +1,107 @@
+//! Chip detection
+//!
+//! The control register is written with all bits cleared and then all bits set, the bits which
+//! follow the written value are the channel select bits. Upper bits which don't follow it are
+//! interrupt inputs or unused. Parts with the same register layout can't be told apart, a
+//! PCA9547 looks like a four channel switch and a PCA9540B like a four channel mux.
+
+use crate::error::{MultiplexerError, Result};
+use embedded_hal::i2c::I2c;
+
+/// Best guess of the chip found on the bus
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChipFamily {
+    /// One bit per channel, e.g. PCA9545A or TCA9548A
+    Switch { channels: u8 },
+    /// An enable bit plus the channel number, e.g. PCA9544A
+    Mux { channels: u8 },
+    /// The register didn't match any known layout
+    Unknown,
+}
+
+impl From<u8> for ChipFamily {
+    fn from(writable: u8) -> Self {
+        match writable {
+            0b1111_1111 => Self::Switch { channels: 8 },
+            0b0000_1111 => Self::Switch { channels: 4 },
+            0b0000_0011 => Self::Switch { channels: 2 },
+            0b0000_0111 => Self::Mux { channels: 4 },
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Probes the chip at `address`, the control register is restored to its prior value before
+/// returning
+pub fn detect_chip<I2C: I2c>(i2c: &mut I2C, address: u8) -> Result<ChipFamily, I2C::Error> {
+    let mut prior = [0];
+    i2c.read(address, &mut prior)
+        .map_err(MultiplexerError::Transfer)?;
+
+    let probed = probe(i2c, address);
+    let restored = i2c
+        .write(address, &prior)
+        .map_err(MultiplexerError::Transfer);
+
+    let writable = probed?;
+    restored?;
+    Ok(writable.into())
+}
+
+fn probe<I2C: I2c>(i2c: &mut I2C, address: u8) -> Result<u8, I2C::Error> {
+    let mut cleared = [0];
+    i2c.write_read(address, &[0b0000_0000], &mut cleared)
+        .map_err(MultiplexerError::Transfer)?;
+    let mut set = [0];
+    i2c.write_read(address, &[0b1111_1111], &mut set)
+        .map_err(MultiplexerError::Transfer)?;
+    Ok(set[0] & !cleared[0])
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use crate::detect::{detect_chip, ChipFamily};
+    use crate::prelude::*;
+    use alloc::vec;
+    use embedded_hal::i2c::ErrorKind;
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+    use rstest::*;
+
+    #[rstest]
+    // TCA9548A
+    #[case(0b0000_0000, 0b1111_1111, ChipFamily::Switch { channels: 8 })]
+    // PCA9545A without interrupts asserted
+    #[case(0b0000_0000, 0b0000_1111, ChipFamily::Switch { channels: 4 })]
+    // PCA9545A with every interrupt asserted reads back 0xFF like a TCA9548A
+    #[case(0b1111_0000, 0b1111_1111, ChipFamily::Switch { channels: 4 })]
+    // PCA9543A with interrupts asserted
+    #[case(0b0011_0000, 0b0011_0011, ChipFamily::Switch { channels: 2 })]
+    // PCA9544A
+    #[case(0b0100_0000, 0b0100_0111, ChipFamily::Mux { channels: 4 })]
+    #[case(0b0000_0000, 0b0000_0000, ChipFamily::Unknown)]
+    fn detect(#[case] cleared: u8, #[case] set: u8, #[case] family: ChipFamily) {
+        let mut i2c = Mock::new(&[
+            Transaction::read(0x70, vec![0b0000_0010]),
+            Transaction::write_read(0x70, vec![0b0000_0000], vec![cleared]),
+            Transaction::write_read(0x70, vec![0b1111_1111], vec![set]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+        ]);
+        assert_eq!(detect_chip(&mut i2c, 0x70), Ok(family));
+        i2c.done();
+    }
+
+    #[test]
+    fn restores_on_error() {
+        let mut i2c = Mock::new(&[
+            Transaction::read(0x71, vec![0b0000_0100]),
+            Transaction::write_read(0x71, vec![0b0000_0000], vec![0]).with_error(ErrorKind::Other),
+            Transaction::write(0x71, vec![0b0000_0100]),
+        ]);
+        assert_eq!(
+            detect_chip(&mut i2c, 0x71),
+            Err(MultiplexerError::Transfer(ErrorKind::Other))
+        );
+        i2c.done();
+    }
+}
@@ -0,0 +1,151 @@
+//! Adapter running embedded-hal 0.2 buses through the embedded-hal 1.0 based types
+
+use crate::error::Eh02BusError;
+use core::fmt::Debug;
+use embedded_hal::i2c::{ErrorType, I2c, Operation, SevenBitAddress};
+use embedded_hal_02::blocking::i2c as i2c02;
+
+/// Most operations [`Eh02Bus`] passes on in one transaction
+pub const MAX_TRANSACTION_OPERATIONS: usize = 8;
+
+/// Wraps an embedded-hal 0.2 bus so it implements `embedded_hal::i2c::I2c`. Transactions run
+/// through the bus's 0.2 `Transactional` impl, so repeated starts are kept
+#[derive(Copy, Clone, Debug)]
+pub struct Eh02Bus<I2C>(pub I2C);
+
+impl<I2C> Eh02Bus<I2C> {
+    /// Returns the wrapped bus
+    pub fn into_inner(self) -> I2C {
+        self.0
+    }
+}
+
+impl<I2C, E> ErrorType for Eh02Bus<I2C>
+where
+    I2C: i2c02::Write<Error = E>,
+    E: Debug,
+{
+    type Error = Eh02BusError<E>;
+}
+
+impl<I2C, E> I2c for Eh02Bus<I2C>
+where
+    I2C: i2c02::Write<Error = E>
+        + i2c02::Read<Error = E>
+        + i2c02::WriteRead<Error = E>
+        + i2c02::Transactional<Error = E>,
+    E: Debug,
+{
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(address, read).map_err(Eh02BusError::Bus)
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(address, write).map_err(Eh02BusError::Bus)
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.0
+            .write_read(address, write, read)
+            .map_err(Eh02BusError::Bus)
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut eh02_operations =
+            heapless::Vec::<i2c02::Operation<'_>, MAX_TRANSACTION_OPERATIONS>::new();
+        for operation in operations {
+            let operation = match operation {
+                Operation::Read(read) => i2c02::Operation::Read(read),
+                Operation::Write(write) => i2c02::Operation::Write(write),
+            };
+            eh02_operations
+                .push(operation)
+                .map_err(|_| Eh02BusError::TooManyOperations)?;
+        }
+        self.0
+            .exec(address, &mut eh02_operations)
+            .map_err(Eh02BusError::Bus)
+    }
+}
+
+#[cfg(all(test, feature = "sync"))]
+pub(crate) mod test {
+    extern crate alloc;
+    use super::Eh02Bus;
+    use alloc::vec::Vec;
+    use embedded_hal::i2c::{I2c, Operation};
+    use embedded_hal_02::blocking::i2c as i2c02;
+    use embedded_hal_mock::eh1::i2c::Mock;
+
+    /// Drives an embedded-hal 1.0 bus through the 0.2 traits, so the shared tests can reuse
+    /// their 1.0 mock expectations
+    pub(crate) struct Eh1As02<I2C>(pub I2C);
+
+    impl<I2C: I2c> i2c02::Write for Eh1As02<I2C> {
+        type Error = I2C::Error;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+            self.0.write(address, bytes)
+        }
+    }
+
+    impl<I2C: I2c> i2c02::Read for Eh1As02<I2C> {
+        type Error = I2C::Error;
+
+        fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+            self.0.read(address, buffer)
+        }
+    }
+
+    impl<I2C: I2c> i2c02::WriteRead for Eh1As02<I2C> {
+        type Error = I2C::Error;
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<(), Self::Error> {
+            self.0.write_read(address, bytes, buffer)
+        }
+    }
+
+    impl<I2C: I2c> i2c02::Transactional for Eh1As02<I2C> {
+        type Error = I2C::Error;
+
+        fn exec(
+            &mut self,
+            address: u8,
+            operations: &mut [i2c02::Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            let mut operations: Vec<_> = operations
+                .iter_mut()
+                .map(|operation| match operation {
+                    i2c02::Operation::Read(read) => Operation::Read(read),
+                    i2c02::Operation::Write(write) => Operation::Write(write),
+                })
+                .collect();
+            self.0.transaction(address, &mut operations)
+        }
+    }
+
+    /// Wraps a bus the same way as `Multiplexer::new_eh02`
+    pub(crate) fn eh02_bus<I2C>(i2c: I2C) -> Eh02Bus<Eh1As02<I2C>> {
+        Eh02Bus(Eh1As02(i2c))
+    }
+
+    impl Eh02Bus<Eh1As02<Mock>> {
+        pub(crate) fn done(&mut self) {
+            self.0 .0.done();
+        }
+    }
+}
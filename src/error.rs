@@ -1,32 +1,183 @@
+use crate::chip::DeviceId;
+#[cfg(feature = "eh1")]
 use embedded_hal::i2c::{Error, ErrorKind};
-use thiserror::Error;
 
 pub type Result<T, I2cError> = core::result::Result<T, MultiplexerError<I2cError>>;
 
-#[derive(Error, Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
-pub enum MultiplexerError<I2cError>
-where
-    I2cError: Error,
-{
-    #[error("Write Read I2C Error")]
-    WriteReadI2CError,
-    #[error("Write I2C Error")]
-    WriteI2CError,
-    #[error("Read I2C Error")]
-    ReadI2CError,
-    #[error("Incorrect port supplied")]
-    PortError,
-    #[error("I2C Error")]
-    I2CError(I2cError),
+#[derive(thiserror::Error, Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub enum MultiplexerError<I2cError> {
+    #[error("Port {0} doesn't exist on this chip")]
+    InvalidPort(u8),
+    #[error("Ports {0:#010b} can't be enabled together on this chip")]
+    UnsupportedPorts(u8),
+    #[error("Channel select I2C error")]
+    Select(I2cError),
+    #[error("Channel select I2C error after {attempts} attempts")]
+    SelectFailed { attempts: u16, error: I2cError },
+    #[error("Transfer I2C error")]
+    Transfer(I2cError),
+    #[error("GPIO pin error")]
+    PinError,
+    #[error("Device ID I2C Error")]
+    DeviceIdError(I2cError),
+    #[error("Unexpected device ID {0:?}")]
+    DeviceIdMismatch(DeviceId),
+    #[error("Operation timed out")]
+    Timeout,
+    #[error("Control register reads {actual:#04x} after selecting {expected:#04x}")]
+    SelectVerificationFailed { expected: u8, actual: u8 },
+    #[error("Address {0:#04x} isn't a 7-bit I2C address")]
+    InvalidAddress(u8),
+    #[error("Address {0:#04x} isn't allowed on this port")]
+    AddressNotAllowed(u16),
+    #[error("Device {0:#04x} didn't acknowledge before the polls ran out")]
+    DeviceBusyTimeout(u8),
+    #[error("Transfers need exactly one enabled port")]
+    AmbiguousRouting,
+    #[error("Reads aren't possible with every port enabled")]
+    BroadcastReadUnsupported,
+}
+
+/// Error of `Multiplexer::for_each_port` and the per port results of `Multiplexer::map_ports`
+#[cfg(feature = "sync")]
+#[derive(thiserror::Error, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ForEachError<E, I2cError> {
+    #[error("Selecting port {port} failed")]
+    Select {
+        port: u8,
+        error: MultiplexerError<I2cError>,
+    },
+    #[error("Port {port} failed")]
+    Port { port: u8, error: E },
+    #[error("Restoring the port states failed")]
+    Restore(MultiplexerError<I2cError>),
+}
+
+/// Error of a `ContextBusPort`, naming the multiplexer and port the operation failed on
+#[cfg(feature = "bus")]
+#[derive(thiserror::Error, Copy, Clone, Debug, Eq, PartialEq)]
+#[error("{stage:?} on port {port} of the multiplexer at {mux:#04x} failed: {error}")]
+pub struct PortOpError<I2cError> {
+    /// Address of the multiplexer
+    pub mux: u8,
+    /// Port, the lowest one for a group
+    pub port: u8,
+    pub stage: PortStage,
+    pub error: MultiplexerError<I2cError>,
+}
+
+/// Step of a port operation which failed
+#[cfg(feature = "bus")]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PortStage {
+    /// Writing the select or deselect byte
+    Select,
+    /// The downstream transfer
+    Transfer,
+    /// Checks before touching the bus, e.g. the allowed addresses
+    Check,
+}
+
+#[cfg(feature = "bus")]
+impl<I2cError> PortOpError<I2cError> {
+    pub(crate) fn new(mux: u8, port: u8, error: MultiplexerError<I2cError>) -> Self {
+        let stage = match error {
+            MultiplexerError::Select(_)
+            | MultiplexerError::SelectFailed { .. }
+            | MultiplexerError::SelectVerificationFailed { .. } => PortStage::Select,
+            MultiplexerError::Transfer(_) => PortStage::Transfer,
+            _ => PortStage::Check,
+        };
+        Self {
+            mux,
+            port,
+            stage,
+            error,
+        }
+    }
+}
+
+#[cfg(feature = "bus")]
+impl<I2cError: Error> Error for PortOpError<I2cError> {
+    fn kind(&self) -> ErrorKind {
+        self.error.kind()
+    }
+}
+
+/// Error of an `ErasedBusPort`, a [`PortOpError`] with the bus error reduced to its kind so
+/// ports over different buses share one error type
+#[cfg(feature = "bus")]
+#[derive(thiserror::Error, Copy, Clone, Debug, Eq, PartialEq)]
+#[error("{stage:?} on port {port} of the multiplexer at {mux:#04x} failed: {kind}")]
+pub struct ErasedPortError {
+    /// Address of the multiplexer
+    pub mux: u8,
+    /// Port, the lowest one for a group
+    pub port: u8,
+    pub stage: PortStage,
+    pub kind: ErrorKind,
+}
+
+#[cfg(feature = "bus")]
+impl<I2cError: Error> From<PortOpError<I2cError>> for ErasedPortError {
+    fn from(error: PortOpError<I2cError>) -> Self {
+        Self {
+            mux: error.mux,
+            port: error.port,
+            stage: error.stage,
+            kind: error.error.kind(),
+        }
+    }
+}
+
+#[cfg(feature = "bus")]
+impl Error for ErasedPortError {
+    fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+/// Error of an embedded-hal 0.2 bus, which has no error kind
+#[cfg(feature = "eh02")]
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Eh02Error<E>(pub E);
+
+#[cfg(all(feature = "eh02", feature = "eh1"))]
+impl<E: core::fmt::Debug> Error for Eh02Error<E> {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// Error of an [`Eh02Bus`](crate::eh02::Eh02Bus)
+#[cfg(all(feature = "eh02", feature = "eh1"))]
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub enum Eh02BusError<E> {
+    /// Error of the wrapped bus, which has no error kind
+    Bus(E),
+    /// The transaction had more than
+    /// [`MAX_TRANSACTION_OPERATIONS`](crate::eh02::MAX_TRANSACTION_OPERATIONS) operations
+    TooManyOperations,
+}
+
+#[cfg(all(feature = "eh02", feature = "eh1"))]
+impl<E: core::fmt::Debug> Error for Eh02BusError<E> {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
 }
 
+#[cfg(feature = "eh1")]
 impl<I2cError> Error for MultiplexerError<I2cError>
 where
     I2cError: Error,
 {
     fn kind(&self) -> ErrorKind {
         match self {
-            Self::I2CError(e) => e.kind(),
+            Self::Select(e)
+            | Self::SelectFailed { error: e, .. }
+            | Self::Transfer(e)
+            | Self::DeviceIdError(e) => e.kind(),
             _ => ErrorKind::Other,
         }
     }
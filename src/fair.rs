@@ -0,0 +1,252 @@
+//! Round-robin access to a shared bus
+//!
+//! A mutex grants the bus to whichever task polls first, so a task which loops over its port
+//! can starve the others. `FairMux` instead hands the bus to the waiting ports in port order,
+//! starting after the port which used it last, so every port gets at most one operation while
+//! another one is waiting.
+
+use crate::bus::{BusPort, MultiplexerBus};
+use crate::chip::{Chip, Pca9546};
+use crate::prelude::MultiplexerError;
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+use embassy_sync::blocking_mutex::{raw::RawMutex, Mutex as BlockingMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use embedded_hal::i2c::{ErrorType, Operation, SevenBitAddress};
+use embedded_hal_async::i2c::I2c as AsyncI2c;
+
+struct Turns {
+    /// Port currently holding the bus
+    holder: Option<u8>,
+    /// Mask of the ports waiting for their turn
+    waiting: u8,
+    /// First port considered for the next turn
+    next: u8,
+    wakers: [WakerRegistration; 8],
+}
+
+impl Turns {
+    /// First waiting port in round-robin order
+    fn first_waiting(&self) -> Option<u8> {
+        (0..8)
+            .map(|offset| (self.next + offset) % 8)
+            .find(|port| self.waiting & (1 << port) != 0)
+    }
+
+    fn wake_all(&mut self) {
+        self.wakers.iter_mut().for_each(WakerRegistration::wake);
+    }
+}
+
+/// Shared bus which grants the ports their turns in round-robin order
+pub struct FairMux<M: RawMutex, I2C, C: Chip = Pca9546> {
+    bus: Mutex<M, I2C>,
+    multiplexer: MultiplexerBus<C>,
+    turns: BlockingMutex<M, RefCell<Turns>>,
+}
+
+impl<M: RawMutex, I2C, C: Chip> FairMux<M, I2C, C> {
+    pub fn new(multiplexer: MultiplexerBus<C>, i2c: I2C) -> Self {
+        Self {
+            bus: Mutex::new(i2c),
+            multiplexer,
+            turns: BlockingMutex::new(RefCell::new(Turns {
+                holder: None,
+                waiting: 0,
+                next: 0,
+                wakers: [const { WakerRegistration::new() }; 8],
+            })),
+        }
+    }
+
+    /// Creates a port which waits for its turn before every operation, panics if the chip
+    /// doesn't have `port`
+    pub fn port(&self, port: u8) -> FairPort<'_, M, I2C, C> {
+        FairPort {
+            fair: self,
+            index: port,
+            port: self.multiplexer.new_port((), port),
+        }
+    }
+
+    /// Returns the wrapped bus
+    pub fn into_inner(self) -> I2C {
+        self.bus.into_inner()
+    }
+
+    /// Waits until no port ahead of `port` in the round-robin order is waiting
+    async fn acquire(&self, port: u8) -> Turn<'_, M, I2C, C> {
+        let mut turn = Turn {
+            fair: self,
+            port,
+            granted: false,
+        };
+        poll_fn(|cx| {
+            self.turns.lock(|turns| {
+                let mut turns = turns.borrow_mut();
+                turns.waiting |= 1 << port;
+                if turns.holder.is_none() && turns.first_waiting() == Some(port) {
+                    turns.waiting &= !(1 << port);
+                    turns.holder = Some(port);
+                    turns.next = (port + 1) % 8;
+                    turn.granted = true;
+                    Poll::Ready(())
+                } else {
+                    turns.wakers[port as usize].register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await;
+        turn
+    }
+}
+
+/// Releases the turn, or leaves the queue if dropped while waiting
+struct Turn<'a, M: RawMutex, I2C, C: Chip> {
+    fair: &'a FairMux<M, I2C, C>,
+    port: u8,
+    granted: bool,
+}
+
+impl<M: RawMutex, I2C, C: Chip> Drop for Turn<'_, M, I2C, C> {
+    fn drop(&mut self) {
+        self.fair.turns.lock(|turns| {
+            let mut turns = turns.borrow_mut();
+            if self.granted {
+                turns.holder = None;
+            } else {
+                turns.waiting &= !(1 << self.port);
+            }
+            turns.wake_all();
+        })
+    }
+}
+
+/// Port of a [`FairMux`]
+pub struct FairPort<'a, M: RawMutex, I2C, C: Chip = Pca9546> {
+    fair: &'a FairMux<M, I2C, C>,
+    index: u8,
+    port: BusPort<()>,
+}
+
+impl<M, I2C, C> ErrorType for FairPort<'_, M, I2C, C>
+where
+    M: RawMutex,
+    I2C: ErrorType,
+    C: Chip,
+{
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<M, I2C, C> AsyncI2c for FairPort<'_, M, I2C, C>
+where
+    M: RawMutex,
+    I2C: AsyncI2c,
+    C: Chip,
+{
+    async fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        let _turn = self.fair.acquire(self.index).await;
+        let mut bus = self.fair.bus.lock().await;
+        self.port.on(&mut *bus).read(address, read).await
+    }
+
+    async fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        let _turn = self.fair.acquire(self.index).await;
+        let mut bus = self.fair.bus.lock().await;
+        self.port.on(&mut *bus).write(address, write).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let _turn = self.fair.acquire(self.index).await;
+        let mut bus = self.fair.bus.lock().await;
+        self.port
+            .on(&mut *bus)
+            .write_read(address, write, read)
+            .await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let _turn = self.fair.acquire(self.index).await;
+        let mut bus = self.fair.bus.lock().await;
+        self.port
+            .on(&mut *bus)
+            .transaction(address, operations)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use crate::prelude::*;
+    use alloc::vec;
+    use embassy_futures::{block_on, join::join3, yield_now};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embedded_hal::i2c::ErrorKind;
+    use embedded_hal_async::i2c::I2c;
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+
+    /// Yields inside every write so the other tasks queue up while the bus is held
+    struct YieldingBus(Mock);
+
+    impl embedded_hal::i2c::ErrorType for YieldingBus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for YieldingBus {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [embedded_hal::i2c::Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            self.0.transaction(address, operations).await
+        }
+
+        async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+            yield_now().await;
+            self.0.write(address, write).await
+        }
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut expectations = vec![];
+        for _ in 0..2 {
+            for port in 0..3 {
+                expectations.push(Transaction::write(0x70, vec![1 << port]));
+                expectations.push(Transaction::write(0x20 + port, vec![port]));
+            }
+        }
+        let i2c = Mock::new(&expectations);
+        let fair = FairMux::<NoopRawMutex, _>::new(MultiplexerBus::new(), YieldingBus(i2c));
+
+        // Each task writes twice in a row, but waits for the other ports in between
+        let task = |port: u8| {
+            let mut fair_port = fair.port(port);
+            async move {
+                for _ in 0..2 {
+                    fair_port.write(0x20 + port, &[port]).await?;
+                }
+                Ok::<_, MultiplexerError<ErrorKind>>(())
+            }
+        };
+        let (first, second, third) = block_on(join3(task(0), task(1), task(2)));
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+        assert!(third.is_ok());
+
+        fair.into_inner().0.done();
+    }
+}
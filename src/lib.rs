@@ -1,22 +1,83 @@
 #![no_std]
 
+#[cfg(any(feature = "sync", feature = "async"))]
+#[macro_use]
+mod maybe_async;
+
+#[cfg(feature = "async")]
+pub mod asynch;
+#[cfg(feature = "sync")]
+pub mod blocking;
 #[cfg(feature = "bus")]
 pub mod bus;
+pub mod chip;
+#[cfg(feature = "sync")]
+pub mod detect;
+#[cfg(all(feature = "eh02", feature = "eh1"))]
+pub mod eh02;
 pub mod error;
+#[cfg(feature = "embassy")]
+pub mod fair;
+#[cfg(feature = "sync")]
+pub mod max735x;
+#[cfg(feature = "sync")]
+pub mod shared;
 
-use embedded_hal::i2c::I2c;
-use error::{MultiplexerError, Result};
+#[cfg(feature = "sync")]
+pub use blocking::Multiplexer;
 
 pub mod prelude {
+    #[cfg(feature = "async")]
+    pub use crate::asynch::{Multiplexer as AsyncMultiplexer, MuxDevice as AsyncMuxDevice};
+    #[cfg(feature = "embassy")]
+    pub use crate::bus::{select_path, AsyncBusPort, AtomicBusPort, PathPort, TimeoutPort};
     #[cfg(feature = "bus")]
-    pub use crate::bus::{BusPort, MultiplexerBus};
-    pub use crate::{error::MultiplexerError, Multiplexer, PortState};
+    pub use crate::bus::{
+        BusEvent, BusOperation, BusOutcome, BusPort, CachedBus, CachedPort, ConstPort,
+        ContextBusPort, DeselectOnDrop, ErasedBusPort, MultiplexerBus, TracingBusPort,
+    };
+    #[cfg(feature = "eh02")]
+    pub use crate::error::Eh02Error;
+    #[cfg(feature = "bus")]
+    pub use crate::error::{ErasedPortError, PortOpError, PortStage};
+    #[cfg(feature = "embassy")]
+    pub use crate::fair::{FairMux, FairPort};
+    #[cfg(any(feature = "sync", feature = "async"))]
+    pub use crate::maybe_async::{PortIter, ScanResult};
+    #[cfg(feature = "sync")]
+    pub use crate::{
+        blocking::{MuxDevice, PortAccess, PortGuard},
+        detect::{detect_chip, ChipFamily},
+        error::ForEachError,
+        shared::{SharedMultiplexer, SharedPort},
+        Multiplexer,
+    };
+    pub use crate::{
+        chip::{
+            AddressPins, Chip, DeviceId, Identify, Interrupts, Max7357, Max7358, Mux, Pca9540,
+            Pca9542, Pca9543, Pca9544, Pca9545, Pca9546, Pca9547, Pca9846, Pca9849, Reset, Switch,
+            Tca9544Status, Tca9548,
+        },
+        error::MultiplexerError,
+        PortSelection, PortSnapshot, PortState,
+    };
+    #[cfg(all(feature = "eh02", feature = "eh1"))]
+    pub use crate::{eh02::Eh02Bus, error::Eh02BusError};
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum PortState {
     Enabled,
     Disabled,
+    /// Nothing was written or read yet, or the last write failed
+    Unknown,
+}
+
+impl PortState {
+    /// State of `port` in a mask of enabled ports
+    pub fn from_mask(mask: u8, port: u8) -> Self {
+        (mask & (1 << port) != 0).into()
+    }
 }
 
 impl From<bool> for PortState {
@@ -28,163 +89,146 @@ impl From<bool> for PortState {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct Multiplexer<I2C: 'static + Send + Sync> {
-    i2c: I2C,
-    address: u8,
-    state: [bool; 4],
-}
-
-pub(crate) fn address_from_pins(a0: bool, a1: bool, a2: bool) -> u8 {
-    let mut address = 0b1110_0000;
-    if a0 {
-        address |= 0b0000_0001;
-    }
-    if a1 {
-        address |= 0b0000_0010;
+/// `Unknown` converts to `false`
+impl From<PortState> for bool {
+    fn from(value: PortState) -> Self {
+        matches!(value, PortState::Enabled)
     }
-    if a2 {
-        address |= 0b0000_0100;
-    }
-    address
 }
 
-impl<I2C> Multiplexer<I2C>
-where
-    I2C: I2c + Send + Sync,
-{
-    pub fn new(i2c: I2C) -> Self {
-        Self {
-            i2c,
-            address: 0x70,
-            state: [false; 4],
-        }
-    }
+/// Saved port states of a multiplexer, bit `n` is port `n`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct PortSnapshot(u8);
 
-    /// Sets the address according to the enabled hardware settings
-    pub fn with_address_pins(mut self, a0: bool, a1: bool, a2: bool) -> Self {
-        self.address = address_from_pins(a0, a1, a2);
-        self
+impl From<u8> for PortSnapshot {
+    fn from(mask: u8) -> Self {
+        Self(mask)
     }
+}
 
-    /// Sets the address
-    pub fn with_address(mut self, address: u8) -> Self {
-        self.address = address;
-        self
+impl From<PortSnapshot> for u8 {
+    fn from(snapshot: PortSnapshot) -> Self {
+        snapshot.0
     }
+}
 
-    fn port_code(states: [bool; 4]) -> u8 {
-        let mut code = 0;
-        if states[0] {
-            code |= 0b000_0001;
-        }
-        if states[1] {
-            code |= 0b000_0010;
-        }
-        if states[2] {
-            code |= 0b000_0100;
-        }
-        if states[3] {
-            code |= 0b000_1000;
-        }
+/// Ports to enable, bit `n` is port `n`. Converts from bool and `PortState` arrays, where
+/// `Unknown` counts as disabled, and from a raw mask
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct PortSelection(u8);
 
-        code
+impl PortSelection {
+    /// State of `port`
+    pub fn state(&self, port: u8) -> PortState {
+        PortState::from_mask(self.0, port)
     }
 }
 
-impl<I2C> Multiplexer<I2C>
-where
-    I2C: I2c + Send + Sync,
-{
-    /// Disables all ports
-    pub fn with_ports_disabled(self) -> Result<Self, I2C::Error> {
-        self.with_ports([false; 4])
+impl From<u8> for PortSelection {
+    fn from(mask: u8) -> Self {
+        Self(mask)
     }
+}
 
-    /// Disables all ports
-    pub fn set_ports_disabled(mut self) -> Result<(), I2C::Error> {
-        self.set_ports([false; 4])
+impl<const N: usize> From<[bool; N]> for PortSelection {
+    fn from(ports: [bool; N]) -> Self {
+        Self(
+            ports
+                .iter()
+                .enumerate()
+                .filter(|(_, enabled)| **enabled)
+                .fold(0, |mask, (port, _)| mask | 1 << port),
+        )
     }
+}
 
-    /// Enables all ports
-    pub fn with_ports_enabled(self) -> Result<Self, I2C::Error> {
-        self.with_ports([true; 4])
+impl<const N: usize> From<[PortState; N]> for PortSelection {
+    fn from(ports: [PortState; N]) -> Self {
+        ports.map(bool::from).into()
     }
+}
 
-    /// Enables all ports
-    pub fn set_ports_enabled(mut self) -> Result<(), I2C::Error> {
-        self.set_ports([true; 4])
+impl From<PortSelection> for u8 {
+    fn from(selection: PortSelection) -> Self {
+        selection.0
     }
+}
 
-    /// Enables / Disables the selected port
-    pub fn set_port(&mut self, port: u8, state: impl Into<bool>) -> Result<(), I2C::Error> {
-        if port >= 4 {
-            return Err(MultiplexerError::PortError);
-        }
-
-        self.state[port as usize] = state.into();
-
-        let code = Self::port_code(self.state);
-
-        self.i2c_write(&[code])
+impl<const N: usize> From<PortSelection> for [bool; N] {
+    fn from(selection: PortSelection) -> Self {
+        core::array::from_fn(|port| selection.0 & 1 << port != 0)
     }
+}
 
-    /// Sets the selected port
-    pub fn with_port(mut self, port: u8, state: impl Into<bool>) -> Result<Self, I2C::Error> {
-        self.set_port(port, state.into())?;
-        Ok(self)
+impl<const N: usize> From<PortSelection> for [PortState; N] {
+    fn from(selection: PortSelection) -> Self {
+        core::array::from_fn(|port| selection.state(port as u8))
     }
+}
 
-    /// Enables / Disables the selected ports
-    pub fn set_ports(&mut self, ports: [bool; 4]) -> Result<(), I2C::Error> {
-        let code = Self::port_code(ports);
-        self.i2c_write(&[code])
+/// Address of a multiplexer with the A0, A1 and A2 pins pulled high or low
+pub const fn address_from_pins(a0: bool, a1: bool, a2: bool) -> u8 {
+    let mut address = 0b0111_0000;
+    if a0 {
+        address |= 0b0000_0001;
     }
-
-    /// Enables / Disables the selected ports
-    pub fn with_ports(mut self, ports: [bool; 4]) -> Result<Self, I2C::Error> {
-        self.set_ports(ports)?;
-        Ok(self)
+    if a1 {
+        address |= 0b0000_0010;
     }
-
-    fn i2c_write(&mut self, bytes: &[u8]) -> Result<(), I2C::Error> {
-        self.i2c
-            .write(self.address, bytes)
-            .map_err(MultiplexerError::I2CError)
+    if a2 {
+        address |= 0b0000_0100;
     }
+    address
 }
 
+/// Address with the A2, A1 and A0 pins low, low, low
+pub const ADDRESS_000: u8 = address_from_pins(false, false, false);
+/// Address with the A2, A1 and A0 pins low, low, high
+pub const ADDRESS_001: u8 = address_from_pins(true, false, false);
+/// Address with the A2, A1 and A0 pins low, high, low
+pub const ADDRESS_010: u8 = address_from_pins(false, true, false);
+/// Address with the A2, A1 and A0 pins low, high, high
+pub const ADDRESS_011: u8 = address_from_pins(true, true, false);
+/// Address with the A2, A1 and A0 pins high, low, low
+pub const ADDRESS_100: u8 = address_from_pins(false, false, true);
+/// Address with the A2, A1 and A0 pins high, low, high
+pub const ADDRESS_101: u8 = address_from_pins(true, false, true);
+/// Address with the A2, A1 and A0 pins high, high, low
+pub const ADDRESS_110: u8 = address_from_pins(false, true, true);
+/// Address with the A2, A1 and A0 pins high, high, high
+pub const ADDRESS_111: u8 = address_from_pins(true, true, true);
+
 #[cfg(test)]
 mod test {
-    use crate::prelude::*;
-    use embedded_hal_mock::common::Generic;
-    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
-    use rstest::*;
-
-    impl Multiplexer<Generic<Transaction>> {
-        fn done(mut self) {
-            self.i2c.done();
+    use crate::{PortSelection, PortState};
+
+    #[test]
+    fn port_selection() {
+        for mask in 0..=0b0000_1111u8 {
+            let bools: [bool; 4] = PortSelection::from(mask).into();
+            let states: [PortState; 4] = PortSelection::from(mask).into();
+            assert_eq!(states, bools.map(PortState::from));
+            assert_eq!(u8::from(PortSelection::from(bools)), mask);
+            assert_eq!(u8::from(PortSelection::from(states)), mask);
         }
-    }
-
-    #[rstest]
-    #[case([true;4], 0b0000_1111)]
-    #[case([false;4], 0b0000_0000)]
-    #[case([true, false, true, false], 0b0000_0101)]
-    fn setup_ports(#[case] ports: [bool; 4], #[case] result: u8) {
-        assert_eq!(Multiplexer::<Mock>::port_code(ports), result)
-    }
-
-    #[rstest]
-    #[case([true;3], 0b1110_0111)]
-    #[case([false;3], 0b1110_0000)]
-    #[case([true, false, false], 0b1110_0001)]
-    #[case([false, true, false], 0b1110_0010)]
-    #[case([true, false, true], 0b1110_0101)]
-    fn setup_address(#[case] addr: [bool; 3], #[case] result: u8) {
-        let i2c = Mock::new(&[]);
-        let multiplexer = Multiplexer::new(i2c).with_address_pins(addr[0], addr[1], addr[2]);
-        assert_eq!(multiplexer.address, result);
-        multiplexer.done();
+        assert_eq!(
+            PortSelection::from([PortState::Unknown, PortState::Enabled]),
+            PortSelection::from(0b0000_0010)
+        );
+    }
+
+    #[test]
+    fn address_constants() {
+        let addresses = [
+            crate::ADDRESS_000,
+            crate::ADDRESS_001,
+            crate::ADDRESS_010,
+            crate::ADDRESS_011,
+            crate::ADDRESS_100,
+            crate::ADDRESS_101,
+            crate::ADDRESS_110,
+            crate::ADDRESS_111,
+        ];
+        assert_eq!(addresses, core::array::from_fn(|i| 0x70 + i as u8));
     }
 }
@@ -0,0 +1,238 @@
+//! MAX735x enhanced mode
+//!
+//! In basic mode these chips are register compatible with the PCA9548A. Their registers are
+//! accessed in sequence, a write sets the switch control register first, then the
+//! configuration register and so on, and a read returns them in the same order. The
+//! configuration register features only take effect once [`ENHANCED_MODE`] is set.
+
+use crate::chip::{EnhancedMode, LockupRecovery, PortArray};
+use crate::error::{MultiplexerError, Result};
+use crate::Multiplexer;
+use embedded_hal::i2c::I2c;
+
+/// Unlocks the enhanced mode features of the configuration register
+pub const ENHANCED_MODE: u8 = 0b0000_0001;
+/// Detects a downstream channel holding the bus low
+pub const LOCKUP_DETECTION: u8 = 0b0000_0010;
+/// Disconnects a channel once a lock-up is detected on it (MAX7358)
+pub const LOCKUP_DISCONNECT: u8 = 0b0000_0100;
+
+const CONFIG_REGISTER: usize = 1;
+const FLUSH_OUT_REGISTER: usize = 2;
+const LOCKUP_STATUS_REGISTER: usize = 3;
+
+impl<I2C, C> Multiplexer<I2C, C>
+where
+    I2C: I2c,
+    C: EnhancedMode,
+{
+    /// Reads the configuration register
+    pub fn read_config(&mut self) -> Result<u8, I2C::Error> {
+        self.read_register(CONFIG_REGISTER)
+    }
+
+    /// Writes the configuration register, the switch control register is rewritten with the
+    /// cached port states, which are known afterwards unless the write fails
+    pub fn write_config(&mut self, config: u8) -> Result<(), I2C::Error> {
+        self.write_registers(&[config])
+    }
+
+    /// Unlocks the enhanced mode features. No separate unlock bytes are sent, the switch
+    /// control and configuration registers are read and both are written back with
+    /// [`ENHANCED_MODE`] set
+    pub fn enable_enhanced_mode(&mut self) -> Result<(), I2C::Error> {
+        self.update_config(ENHANCED_MODE, true)
+    }
+
+    /// Enables / Disables bus lock-up detection, enabling it also enables enhanced mode
+    pub fn set_lockup_detection(&mut self, enabled: bool) -> Result<(), I2C::Error> {
+        if enabled {
+            self.update_config(ENHANCED_MODE | LOCKUP_DETECTION, true)
+        } else {
+            self.update_config(LOCKUP_DETECTION, false)
+        }
+    }
+
+    fn update_config(&mut self, bits: u8, set: bool) -> Result<(), I2C::Error> {
+        let config = self.read_config()?;
+        self.write_config(if set { config | bits } else { config & !bits })
+    }
+
+    /// Writes the switch control register followed by `registers`, like a port write the
+    /// state is known afterwards unless the write fails
+    fn write_registers(&mut self, registers: &[u8]) -> Result<(), I2C::Error> {
+        let mut buffer = [0; FLUSH_OUT_REGISTER + 1];
+        buffer[0] = self.control_byte(self.state)?;
+        buffer[1..=registers.len()].copy_from_slice(registers);
+        let result = self.i2c_write(&buffer[..=registers.len()]);
+        self.known = result.is_ok();
+        if result.is_ok() {
+            self.dirty = false;
+        }
+        result
+    }
+
+    /// Reads every register up to and including the requested one
+    fn read_register(&mut self, register: usize) -> Result<u8, I2C::Error> {
+        let mut registers = [0; LOCKUP_STATUS_REGISTER + 1];
+        self.i2c_read(&mut registers[..=register])?;
+        Ok(registers[register])
+    }
+}
+
+impl<I2C, C> Multiplexer<I2C, C>
+where
+    I2C: I2c,
+    C: LockupRecovery,
+{
+    /// Enables / Disables disconnecting locked up channels, enabling it also enables lock-up
+    /// detection and enhanced mode
+    pub fn set_lockup_disconnect(&mut self, enabled: bool) -> Result<(), I2C::Error> {
+        if enabled {
+            self.update_config(ENHANCED_MODE | LOCKUP_DETECTION | LOCKUP_DISCONNECT, true)
+        } else {
+            self.update_config(LOCKUP_DISCONNECT, false)
+        }
+    }
+
+    /// Reads which channels were disconnected because they were locked up
+    pub fn stuck_channels(&mut self) -> Result<C::Ports<bool>, I2C::Error> {
+        let status = self.read_register(LOCKUP_STATUS_REGISTER)?;
+        Ok(PortArray::from_fn(|port| status & (1 << port) != 0))
+    }
+
+    /// Clocks out the selected channel to release a device holding the bus low
+    pub fn flush_out(&mut self, port: u8) -> Result<(), I2C::Error> {
+        if port >= C::CHANNELS {
+            return Err(MultiplexerError::InvalidPort(port));
+        }
+
+        let config = self.read_config()?;
+        self.write_registers(&[config, 1 << port])
+    }
+
+    /// Connects a channel again after it was disconnected because of a lock-up
+    pub fn reconnect(&mut self, port: u8) -> Result<(), I2C::Error> {
+        self.set_port(port, true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use crate::max735x::{ENHANCED_MODE, LOCKUP_DETECTION, LOCKUP_DISCONNECT};
+    use crate::prelude::*;
+    use alloc::vec;
+    use embedded_hal::i2c::ErrorKind;
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+
+    /// Every byte on the wire when unlocking enhanced mode
+    #[test]
+    fn enable_enhanced_mode() {
+        let i2c = Mock::new(&[
+            Transaction::read(0x70, vec![0b0000_0000, 0b0000_0000]),
+            Transaction::write(0x70, vec![0b0000_0000, ENHANCED_MODE]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Max7357);
+        assert!(multiplexer.enable_enhanced_mode().is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn write_config_state() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0000, ENHANCED_MODE]),
+            Transaction::write(0x70, vec![0b0000_0000, 0b0000_0000]).with_error(ErrorKind::Bus),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Max7357);
+        assert_eq!(multiplexer.port_state(2), Ok(PortState::Unknown));
+        assert!(multiplexer.write_config(ENHANCED_MODE).is_ok());
+        assert_eq!(multiplexer.port_state(2), Ok(PortState::Disabled));
+        assert_eq!(
+            multiplexer.write_config(0b0000_0000),
+            Err(MultiplexerError::Select(ErrorKind::Bus))
+        );
+        assert_eq!(multiplexer.port_state(2), Ok(PortState::Unknown));
+        multiplexer.done();
+    }
+
+    #[test]
+    fn lockup_detection() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0010_0000]),
+            Transaction::read(0x70, vec![0b0010_0000, 0b0100_0000]),
+            Transaction::write(0x70, vec![0b0010_0000, 0b0100_0011]),
+            Transaction::read(0x70, vec![0b0010_0000, 0b0100_0011]),
+            Transaction::write(0x70, vec![0b0010_0000, 0b0100_0001]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Max7357);
+        assert!(multiplexer.set_port(5, true).is_ok());
+        assert!(multiplexer.set_lockup_detection(true).is_ok());
+        assert!(multiplexer.set_lockup_detection(false).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn read_config() {
+        let i2c = Mock::new(&[Transaction::read(
+            0x70,
+            vec![0b0000_0000, ENHANCED_MODE | LOCKUP_DETECTION],
+        )]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Max7357);
+        assert_eq!(
+            multiplexer.read_config(),
+            Ok(ENHANCED_MODE | LOCKUP_DETECTION)
+        );
+        multiplexer.done();
+    }
+
+    #[test]
+    fn lockup_disconnect() {
+        let i2c = Mock::new(&[
+            Transaction::read(0x70, vec![0b0000_0000, 0b0000_0000]),
+            Transaction::write(
+                0x70,
+                vec![
+                    0b0000_0000,
+                    ENHANCED_MODE | LOCKUP_DETECTION | LOCKUP_DISCONNECT,
+                ],
+            ),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Max7358);
+        assert!(multiplexer.set_lockup_disconnect(true).is_ok());
+        multiplexer.done();
+    }
+
+    #[test]
+    fn stuck_channels() {
+        let i2c = Mock::new(&[Transaction::read(
+            0x70,
+            vec![0b0000_0000, 0b0000_0111, 0b0000_0000, 0b1000_0010],
+        )]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Max7358);
+        assert_eq!(
+            multiplexer.stuck_channels(),
+            Ok([false, true, false, false, false, false, false, true])
+        );
+        multiplexer.done();
+    }
+
+    #[test]
+    fn flush_out_and_reconnect() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::read(0x70, vec![0b0000_0000, 0b0000_0111]),
+            Transaction::write(0x70, vec![0b0000_0010, 0b0000_0111, 0b0000_0010]),
+            Transaction::write(0x70, vec![0b0000_0010]),
+        ]);
+        let mut multiplexer = Multiplexer::new(i2c).with_chip(Max7358);
+        assert!(multiplexer.set_port(1, true).is_ok());
+        assert!(multiplexer.flush_out(1).is_ok());
+        assert_eq!(
+            multiplexer.flush_out(8),
+            Err(MultiplexerError::InvalidPort(8))
+        );
+        assert!(multiplexer.reconnect(1).is_ok());
+        multiplexer.done();
+    }
+}
@@ -0,0 +1,2030 @@
+//! Shared blocking / async implementation of `Multiplexer`
+//!
+//! `multiplexer_impl!` expands into a `Multiplexer` struct and its impls inside
+//! [`blocking`](crate::blocking) and [`asynch`](crate::asynch). The async expansion passes
+//! `async` and `.await`, the blocking one passes nothing.
+
+use crate::chip::{ChannelEncoder, Chip};
+use crate::error::{MultiplexerError, Result};
+
+pub(crate) fn port_code<C: Chip>(states: C::Ports<bool>) -> u8 {
+    states
+        .as_ref()
+        .iter()
+        .enumerate()
+        .filter(|(_, enabled)| **enabled)
+        .fold(0, |code, (port, _)| code | 1 << port)
+}
+
+pub(crate) fn switch_port<C: Chip>(state: u8, port: u8, enabled: bool) -> u8 {
+    if enabled {
+        // Chips that can't enable both ports only keep the new one
+        let enabled = state | 1 << port;
+        match C::Encoder::select_mask(enabled) {
+            Some(_) => enabled,
+            None => 1 << port,
+        }
+    } else {
+        state & !(1 << port)
+    }
+}
+
+pub(crate) fn control_byte<C: Chip, E: embedded_hal::i2c::Error>(
+    mask: u8,
+    deselect: u8,
+) -> Result<u8, E> {
+    if mask == 0 {
+        return Ok(deselect);
+    }
+    C::Encoder::select_mask(mask).ok_or(MultiplexerError::UnsupportedPorts(mask))
+}
+
+pub(crate) use crate::chip::all_ports;
+
+/// Mask of the enabled ports in a control byte read back from the chip, interrupt bits dropped
+pub(crate) fn decode_mask<C: Chip>(code: u8) -> u8 {
+    C::Encoder::decode(code) & all_ports::<C>()
+}
+
+/// Reserved address used by the I2C Device ID sequence
+pub(crate) const DEVICE_ID_ADDRESS: u8 = 0x7C;
+
+/// Addresses which aren't reserved by the I2C specification
+pub(crate) const SCAN_ADDRESSES: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+/// Devices found on a single port
+pub type ScanResult = heapless::Vec<u8, 112>;
+
+/// Iterator over the cached `(port, state)` pairs of a multiplexer, in port order
+#[derive(Clone, Debug)]
+pub struct PortIter {
+    ports: core::ops::Range<u8>,
+    state: u8,
+    known: bool,
+}
+
+impl PortIter {
+    pub(crate) fn new(channels: u8, state: u8, known: bool) -> Self {
+        Self {
+            ports: 0..channels,
+            state,
+            known,
+        }
+    }
+}
+
+impl Iterator for PortIter {
+    type Item = (u8, crate::PortState);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let port = self.ports.next()?;
+        Some((port, cached_state(self.state, self.known, port)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.ports.size_hint()
+    }
+}
+
+impl ExactSizeIterator for PortIter {}
+
+pub(crate) fn cached_state(state: u8, known: bool, port: u8) -> crate::PortState {
+    match known {
+        true => crate::PortState::from_mask(state, port),
+        false => crate::PortState::Unknown,
+    }
+}
+
+macro_rules! multiplexer_impl {
+    (
+        $($async:ident)?; $(.$await:ident)?;
+        I2c = $I2c:path, DelayNs = $DelayNs:path
+    ) => {
+        use $crate::chip::{
+            AddressPins, ChannelEncoder, Chip, DeviceId, Identify, Interrupts, Pca9544, Pca9546,
+            PortArray, Reset, Switch, Tca9544Status,
+        };
+        use $crate::error::{MultiplexerError, Result};
+        use $crate::{address_from_pins, PortSelection, PortSnapshot, PortState};
+        use $crate::maybe_async::{
+            all_ports, cached_state, control_byte, decode_mask, port_code, switch_port, PortIter, ScanResult, DEVICE_ID_ADDRESS, SCAN_ADDRESSES,
+        };
+        use core::ops::RangeInclusive;
+        use embedded_hal::i2c::{Error as _, ErrorKind, Operation};
+        use core::marker::PhantomData;
+        use embedded_hal::digital::OutputPin;
+
+        #[derive(Copy, Clone, Debug)]
+        pub struct Multiplexer<I2C, C: Chip = Pca9546> {
+            pub(crate) i2c: I2C,
+            pub(crate) address: u8,
+            /// Assumed enabled ports, only reported while `known`
+            pub(crate) state: u8,
+            pub(crate) known: bool,
+            pub(crate) lazy: bool,
+            pub(crate) dirty: bool,
+            pub(crate) deselect: u8,
+            pub(crate) control_register: Option<u8>,
+            pub(crate) verify: bool,
+            pub(crate) chip: PhantomData<C>,
+        }
+
+        #[cfg(test)]
+        impl<C: Chip> Multiplexer<embedded_hal_mock::common::Generic<embedded_hal_mock::eh1::i2c::Transaction>, C> {
+            pub(crate) fn done(mut self) {
+                self.i2c.done();
+            }
+        }
+
+        impl<I2C> Multiplexer<I2C>
+        where
+            I2C: $I2c,
+        {
+            pub fn new(i2c: I2C) -> Self {
+                Self {
+                    i2c,
+                    address: 0x70,
+                    state: Pca9546::POWER_ON_PORTS,
+                    known: false,
+                    lazy: false,
+                    dirty: false,
+                    deselect: <Pca9546 as Chip>::Encoder::deselect(),
+                    control_register: None,
+                    verify: false,
+                    chip: PhantomData,
+                }
+            }
+
+            /// Creates the multiplexer at `address` and disables every port, which also checks
+            /// that a chip acknowledges there. On failure the bus is handed back with the error,
+            /// e.g. to try the next address
+            pub $($async)? fn new_checked(
+                i2c: I2C,
+                address: u8,
+            ) -> core::result::Result<Self, (I2C, MultiplexerError<I2C::Error>)> {
+                let mut multiplexer = Self::new(i2c);
+                if let Err(e) = multiplexer.set_address(address) {
+                    return Err((multiplexer.i2c, e));
+                }
+                match multiplexer.deselect_all() $(.$await)? {
+                    Ok(()) => Ok(multiplexer),
+                    Err(e) => Err((multiplexer.i2c, e)),
+                }
+            }
+
+            /// Creates the multiplexer at `address` with its cached state read from the chip,
+            /// e.g. on a warm boot where ports may still be enabled
+            pub $($async)? fn new_from_device(i2c: I2C, address: u8) -> Result<Self, I2C::Error> {
+                Self::new(i2c).with_address(address).with_device_state() $(.$await)?
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            /// Sets the chip model, e.g. `with_chip(Tca9548)` for an eight channel part
+            pub fn with_chip<D: Chip>(self, _chip: D) -> Multiplexer<I2C, D> {
+                Multiplexer {
+                    i2c: self.i2c,
+                    address: self.address,
+                    state: D::POWER_ON_PORTS,
+                    known: false,
+                    lazy: self.lazy,
+                    dirty: false,
+                    deselect: D::Encoder::deselect(),
+                    control_register: self.control_register,
+                    verify: self.verify,
+                    chip: PhantomData,
+                }
+            }
+
+            /// Sets the address
+            pub fn with_address(mut self, address: u8) -> Self {
+                self.address = address;
+                self
+            }
+
+            /// Reads the control register back after every `set_port` / `set_ports` write and
+            /// fails with [`MultiplexerError::SelectVerificationFailed`] when it doesn't match
+            pub fn with_verification(mut self, verify: bool) -> Self {
+                self.verify = verify;
+                self
+            }
+
+            /// Splits into a [`MultiplexerBus`](crate::bus::MultiplexerBus) with the same chip,
+            /// address, deselect byte and control register, and the bus to share between its
+            /// ports
+            #[cfg(feature = "bus")]
+            pub fn into_bus(self) -> ($crate::bus::MultiplexerBus<C>, I2C) {
+                let bus = $crate::bus::MultiplexerBus::from_parts(
+                    self.address,
+                    self.deselect,
+                    self.control_register,
+                );
+                (bus, self.i2c)
+            }
+
+            /// Returns the bus, the ports are left as they are
+            pub fn free(self) -> I2C {
+                self.i2c
+            }
+
+            /// Makes the port setters only update the cached state, [`commit`](Self::commit)
+            /// writes it
+            pub fn with_lazy_writes(mut self) -> Self {
+                self.lazy = true;
+                self
+            }
+
+            /// Replaces the cached state without any I2C traffic. The caller is trusted to know
+            /// what the chip has enabled, a wrong state is only corrected by the next write
+            pub fn assume_state(&mut self, ports: impl Into<PortSelection>) {
+                self.state = ports.into().into();
+                self.known = true;
+                self.dirty = false;
+            }
+
+            /// Replaces the cached state without any I2C traffic, see
+            /// [`assume_state`](Self::assume_state)
+            pub fn with_assumed_state(mut self, ports: impl Into<PortSelection>) -> Self {
+                self.assume_state(ports);
+                self
+            }
+
+            /// Whether the cached state has changes which weren't committed yet
+            pub fn is_dirty(&self) -> bool {
+                self.dirty
+            }
+
+            /// Configured address
+            pub fn address(&self) -> u8 {
+                self.address
+            }
+
+            /// Changes the address, e.g. once the right one was detected at runtime. The cached
+            /// state belongs to the previous device, so every port reports `Unknown` afterwards
+            pub fn set_address(&mut self, address: u8) -> Result<(), I2C::Error> {
+                if address > 0x7F {
+                    return Err(MultiplexerError::InvalidAddress(address));
+                }
+                self.address = address;
+                self.known = false;
+                self.dirty = false;
+                Ok(())
+            }
+
+            /// Cached state of `port`, no I2C traffic. `Unknown` until a write or read
+            /// succeeded and after a failed write
+            pub fn port_state(&self, port: u8) -> Result<PortState, I2C::Error> {
+                if port >= C::CHANNELS {
+                    return Err(MultiplexerError::InvalidPort(port));
+                }
+                Ok(self.cached_state(port))
+            }
+
+            /// Cached state of every port, no I2C traffic
+            pub fn states(&self) -> C::Ports<PortState> {
+                PortArray::from_fn(|port| self.cached_state(port as u8))
+            }
+
+            /// Cached state of `port`, `None` if it is out of range
+            pub fn get(&self, port: u8) -> Option<PortState> {
+                self.port_state(port).ok()
+            }
+
+            /// Iterates over the cached `(port, state)` pairs, no I2C traffic
+            pub fn iter(&self) -> PortIter {
+                PortIter::new(C::CHANNELS, self.state, self.known)
+            }
+
+            fn cached_state(&self, port: u8) -> PortState {
+                cached_state(self.state, self.known, port)
+            }
+
+            /// Cached state as a bitmask, bit `n` is port `n`
+            pub fn mask(&self) -> u8 {
+                self.state
+            }
+
+            /// Saves the cached port states so they can be restored later
+            pub fn save_state(&self) -> PortSnapshot {
+                self.state.into()
+            }
+
+            /// Sets the control byte written when every port is disabled, some clones need a
+            /// specific value
+            pub fn with_deselect_byte(mut self, deselect: u8) -> Self {
+                self.deselect = deselect;
+                self
+            }
+
+            /// Prefixes every control register access with `register`, for parts whose channel
+            /// select lives behind a register address
+            pub fn with_control_register(mut self, register: u8) -> Self {
+                self.control_register = Some(register);
+                self
+            }
+        }
+
+        /// Cached state of a port
+        ///
+        /// # Panics
+        ///
+        /// If the port is out of range, use [`Multiplexer::get`] to check it instead
+        impl<I2C, C> core::ops::Index<u8> for Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            type Output = PortState;
+
+            fn index(&self, port: u8) -> &PortState {
+                match self.get(port) {
+                    Some(PortState::Enabled) => &PortState::Enabled,
+                    Some(PortState::Disabled) => &PortState::Disabled,
+                    Some(PortState::Unknown) => &PortState::Unknown,
+                    None => panic!("port {} out of range", port),
+                }
+            }
+        }
+
+        impl<'a, I2C, C> IntoIterator for &'a Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            type Item = (u8, PortState);
+            type IntoIter = PortIter;
+
+            fn into_iter(self) -> PortIter {
+                self.iter()
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            /// Disables all ports
+            pub $($async)? fn with_ports_disabled(self) -> Result<Self, I2C::Error> {
+                self.with_ports(0) $(.$await)?
+            }
+
+            /// Disables all ports
+            pub $($async)? fn set_ports_disabled(&mut self) -> Result<(), I2C::Error> {
+                self.set_ports(0) $(.$await)?
+            }
+
+            /// Enables / Disables the selected port, on single channel chips enabling a port
+            /// disables the others
+            pub $($async)? fn set_port(
+                &mut self,
+                port: u8,
+                state: impl Into<bool>,
+            ) -> Result<(), I2C::Error> {
+                if port >= C::CHANNELS {
+                    return Err(MultiplexerError::InvalidPort(port));
+                }
+
+                let state = switch_port::<C>(self.state, port, state.into());
+                self.write_state(state) $(.$await)?
+            }
+
+            /// Sets the selected port
+            pub $($async)? fn with_port(
+                mut self,
+                port: u8,
+                state: impl Into<bool>,
+            ) -> Result<Self, I2C::Error> {
+                self.set_port(port, state.into()) $(.$await)??;
+                Ok(self)
+            }
+
+            /// Inverts the cached state of the selected port and returns the new state, an
+            /// `Unknown` port is toggled from its assumed state
+            pub $($async)? fn toggle_port(&mut self, port: u8) -> Result<PortState, I2C::Error> {
+                self.port_state(port)?;
+                let state = self.state & 1 << port == 0;
+                self.set_port(port, state) $(.$await)??;
+                Ok(state.into())
+            }
+
+            /// Enables / Disables the selected ports, given as a bool or `PortState` array or a
+            /// mask. Single channel chips only accept one enabled port
+            pub $($async)? fn set_ports(
+                &mut self,
+                ports: impl Into<PortSelection>,
+            ) -> Result<(), I2C::Error> {
+                self.set_mask(ports.into().into()) $(.$await)?
+            }
+
+            /// Enables the ports set in `mask`, bit `n` is port `n`. Bits above the channel
+            /// count are rejected
+            pub $($async)? fn set_mask(&mut self, mask: u8) -> Result<(), I2C::Error> {
+                if mask & !all_ports::<C>() != 0 {
+                    return Err(MultiplexerError::UnsupportedPorts(mask));
+                }
+                self.write_state(mask) $(.$await)?
+            }
+
+            /// Puts the ports back to a state saved with `save_state` in a single write
+            pub $($async)? fn restore_state(&mut self, snapshot: &PortSnapshot) -> Result<(), I2C::Error> {
+                self.set_mask((*snapshot).into()) $(.$await)?
+            }
+
+            /// Sets every port to its `PortState`, same as [`with_ports`](Self::with_ports)
+            pub $($async)? fn with_state<const N: usize>(
+                self,
+                states: [PortState; N],
+            ) -> Result<Self, I2C::Error> {
+                self.with_ports(states) $(.$await)?
+            }
+
+            /// Sets every port to `state`, `Unknown` disables them
+            pub $($async)? fn with_all(self, state: PortState) -> Result<Self, I2C::Error> {
+                let mask = match state {
+                    PortState::Enabled => all_ports::<C>(),
+                    PortState::Disabled | PortState::Unknown => 0,
+                };
+                self.with_ports(mask) $(.$await)?
+            }
+
+            /// Enables the ports set in `mask`
+            pub $($async)? fn with_mask(mut self, mask: u8) -> Result<Self, I2C::Error> {
+                self.set_mask(mask) $(.$await)??;
+                Ok(self)
+            }
+
+            /// Lets `f` edit the cached port states and writes the result at once, nothing is
+            /// written if `f` leaves known states unchanged
+            pub $($async)? fn modify_ports(
+                &mut self,
+                f: impl FnOnce(&mut C::Ports<bool>),
+            ) -> Result<(), I2C::Error> {
+                let mut ports = PortArray::from_fn(|port| self.state & 1 << port != 0);
+                f(&mut ports);
+                let state = port_code::<C>(ports);
+                if self.known && state == self.state {
+                    return Ok(());
+                }
+                self.write_state(state) $(.$await)?
+            }
+
+            /// Enables every listed port with a single write, nothing is written if any port
+            /// is out of range
+            pub $($async)? fn enable_ports(&mut self, ports: &[u8]) -> Result<(), I2C::Error> {
+                self.update_ports(ports, true) $(.$await)?
+            }
+
+            /// Disables every listed port with a single write, nothing is written if any port
+            /// is out of range
+            pub $($async)? fn disable_ports(&mut self, ports: &[u8]) -> Result<(), I2C::Error> {
+                self.update_ports(ports, false) $(.$await)?
+            }
+
+            $($async)? fn update_ports(&mut self, ports: &[u8], enabled: bool) -> Result<(), I2C::Error> {
+                if let Some(&port) = ports.iter().find(|&&port| port >= C::CHANNELS) {
+                    return Err(MultiplexerError::InvalidPort(port));
+                }
+                self.modify_ports(|states| {
+                    for &port in ports {
+                        states.as_mut()[port as usize] = enabled;
+                    }
+                })
+                $(.$await)?
+            }
+
+            /// Enables / Disables the selected ports
+            pub $($async)? fn with_ports(
+                mut self,
+                ports: impl Into<PortSelection>,
+            ) -> Result<Self, I2C::Error> {
+                self.set_ports(ports) $(.$await)??;
+                Ok(self)
+            }
+
+            pub(crate) $($async)? fn i2c_read(&mut self, buffer: &mut [u8]) -> Result<(), I2C::Error> {
+                match self.control_register {
+                    Some(register) => {
+                        self.i2c.write_read(self.address, &[register], buffer) $(.$await)?
+                    }
+                    None => self.i2c.read(self.address, buffer) $(.$await)?,
+                }
+                .map_err(MultiplexerError::Transfer)
+            }
+
+            /// Writes the control byte for `state` and caches it once the write, and the
+            /// verification if enabled, succeeded. A failed write leaves the state unknown. In
+            /// lazy mode only the cache is updated
+            $($async)? fn write_state(&mut self, state: u8) -> Result<(), I2C::Error> {
+                if self.lazy {
+                    // Unsupported states are rejected before they are cached
+                    self.control_byte(state)?;
+                    self.dirty |= !self.known || state != self.state;
+                    self.state = state;
+                    self.known = true;
+                    return Ok(());
+                }
+                self.write_mask(state) $(.$await)?
+            }
+
+            /// Writes `state` right away, ignoring lazy mode
+            $($async)? fn write_mask(&mut self, state: u8) -> Result<(), I2C::Error> {
+                let code = self.control_byte(state)?;
+                let result = self.write_code(code, state) $(.$await)?;
+                self.known = result.is_ok();
+                if result.is_ok() {
+                    self.state = state;
+                    self.dirty = false;
+                }
+                result
+            }
+
+            /// Writes the state cached by the port setters in lazy mode, nothing is written if
+            /// it has no pending changes
+            pub $($async)? fn commit(&mut self) -> Result<(), I2C::Error> {
+                if !self.dirty {
+                    return Ok(());
+                }
+                self.write_mask(self.state) $(.$await)?
+            }
+
+            $($async)? fn write_code(&mut self, code: u8, state: u8) -> Result<(), I2C::Error> {
+                self.i2c_write(&[code]) $(.$await)??;
+                if self.verify {
+                    let mut actual = [0];
+                    self.i2c_read(&mut actual) $(.$await)??;
+                    if decode_mask::<C>(actual[0]) != state {
+                        return Err(MultiplexerError::SelectVerificationFailed {
+                            expected: code,
+                            actual: actual[0],
+                        });
+                    }
+                }
+                Ok(())
+            }
+
+            pub(crate) fn control_byte(&self, mask: u8) -> Result<u8, I2C::Error> {
+                control_byte::<C, _>(mask, self.deselect)
+            }
+
+            pub(crate) $($async)? fn i2c_write(&mut self, bytes: &[u8]) -> Result<(), I2C::Error> {
+                match self.control_register {
+                    Some(register) => {
+                        // Longest write is the MAX7358 flush out sequence
+                        let mut buffer = [register, 0, 0, 0];
+                        buffer[1..=bytes.len()].copy_from_slice(bytes);
+                        self.i2c.write(self.address, &buffer[..=bytes.len()]) $(.$await)?
+                    }
+                    None => self.i2c.write(self.address, bytes) $(.$await)?,
+                }
+                .map_err(MultiplexerError::Select)
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            /// Writes `byte` to the control register as is. The cached state can't follow raw
+            /// bytes, so every port reports `Unknown` afterwards until the next port write or
+            /// [`sync_from_hardware`](Self::sync_from_hardware)
+            pub $($async)? fn write_control(&mut self, byte: u8) -> Result<(), I2C::Error> {
+                self.known = false;
+                self.dirty = false;
+                self.i2c_write(&[byte]) $(.$await)?
+            }
+
+            /// Reads the control register as is, including any interrupt bits
+            pub $($async)? fn read_control(&mut self) -> Result<u8, I2C::Error> {
+                let mut byte = [0];
+                self.i2c_read(&mut byte) $(.$await)??;
+                Ok(byte[0])
+            }
+
+            /// Underlying bus, e.g. to talk to the devices behind the enabled ports directly
+            pub fn bus_mut(&mut self) -> &mut I2C {
+                &mut self.i2c
+            }
+
+            /// Disables every port and returns the bus, on failure the multiplexer is handed back
+            /// with the error
+            pub $($async)? fn deselect_and_free(
+                mut self,
+            ) -> core::result::Result<I2C, (Self, MultiplexerError<I2C::Error>)> {
+                match self.deselect_all() $(.$await)? {
+                    Ok(()) => Ok(self.i2c),
+                    Err(e) => Err((self, e)),
+                }
+            }
+
+            /// Enables `port` and disables every other port with a single write, regardless of
+            /// the cached state or lazy mode
+            pub $($async)? fn select_only(&mut self, port: u8) -> Result<(), I2C::Error> {
+                if port >= C::CHANNELS {
+                    return Err(MultiplexerError::InvalidPort(port));
+                }
+                self.write_mask(1 << port) $(.$await)?
+            }
+
+            /// Disables every port with a single write, regardless of the cached state or lazy
+            /// mode
+            pub $($async)? fn deselect_all(&mut self) -> Result<(), I2C::Error> {
+                self.write_mask(0) $(.$await)?
+            }
+
+            /// Brings the chip back to its power on topology with every port disabled, for error
+            /// recovery when the cached state can't be trusted. The write is never skipped and is
+            /// read back when verification is enabled, the state is `Unknown` if either fails
+            pub $($async)? fn reset_to_default(&mut self) -> Result<(), I2C::Error> {
+                self.known = false;
+                self.dirty = false;
+                self.write_mask(0) $(.$await)?
+            }
+
+            /// Enables only `port` and runs `operations` as one transaction on the device at
+            /// `address` behind it, so mux unaware drivers can be routed per port. An
+            /// embedded-hal 0.2 bus runs them through its `Transactional` impl
+            pub $($async)? fn exec_on(
+                &mut self,
+                port: u8,
+                address: u8,
+                operations: &mut [Operation<'_>],
+            ) -> Result<(), I2C::Error> {
+                self.select_only(port) $(.$await)??;
+                self.i2c
+                    .transaction(address, operations)
+                    $(.$await)?
+                    .map_err(MultiplexerError::Transfer)
+            }
+
+            /// Like [`exec_on`](Self::exec_on), but an empty `operations` slice is a no-op which
+            /// doesn't select the port
+            pub $($async)? fn transaction_on(
+                &mut self,
+                port: u8,
+                address: u8,
+                operations: &mut [Operation<'_>],
+            ) -> Result<(), I2C::Error> {
+                if port >= C::CHANNELS {
+                    return Err(MultiplexerError::InvalidPort(port));
+                }
+                if operations.is_empty() {
+                    return Ok(());
+                }
+                self.exec_on(port, address, operations) $(.$await)?
+            }
+
+            /// Enables only `port` and writes `bytes` to the device at `address` behind it
+            pub $($async)? fn write_on(
+                &mut self,
+                port: u8,
+                address: u8,
+                bytes: &[u8],
+            ) -> Result<(), I2C::Error> {
+                self.select_only(port) $(.$await)??;
+                self.i2c
+                    .write(address, bytes)
+                    $(.$await)?
+                    .map_err(MultiplexerError::Transfer)
+            }
+
+            /// Enables only `port` and reads `buffer` from the device at `address` behind it
+            pub $($async)? fn read_on(
+                &mut self,
+                port: u8,
+                address: u8,
+                buffer: &mut [u8],
+            ) -> Result<(), I2C::Error> {
+                self.select_only(port) $(.$await)??;
+                self.i2c
+                    .read(address, buffer)
+                    $(.$await)?
+                    .map_err(MultiplexerError::Transfer)
+            }
+
+            /// Enables only `port` and writes `bytes` then reads `buffer` with a repeated start
+            /// on the device at `address` behind it
+            pub $($async)? fn write_read_on(
+                &mut self,
+                port: u8,
+                address: u8,
+                bytes: &[u8],
+                buffer: &mut [u8],
+            ) -> Result<(), I2C::Error> {
+                self.select_only(port) $(.$await)??;
+                self.i2c
+                    .write_read(address, bytes, buffer)
+                    $(.$await)?
+                    .map_err(MultiplexerError::Transfer)
+            }
+
+            /// Creates a handle for the device at `address` behind `port`, which enables only
+            /// `port` before each of its transfers
+            pub fn device(&mut self, port: u8, address: u8) -> MuxDevice<'_, I2C, C> {
+                MuxDevice {
+                    multiplexer: self,
+                    port,
+                    address,
+                }
+            }
+
+            /// Reads which ports the chip has enabled, the cached state is left alone
+            pub $($async)? fn read_ports(&mut self) -> Result<C::Ports<PortState>, I2C::Error> {
+                let mask = self.read_mask() $(.$await)??;
+                Ok(PortArray::from_fn(|port| PortState::from_mask(mask, port as u8)))
+            }
+
+            /// Reads which ports the chip has enabled and replaces the cached state with it,
+            /// e.g. after the chip was reset behind the multiplexer's back
+            pub $($async)? fn sync_from_hardware(&mut self) -> Result<C::Ports<PortState>, I2C::Error> {
+                self.state = self.read_mask() $(.$await)??;
+                self.known = true;
+                self.dirty = false;
+                Ok(self.states())
+            }
+
+            /// Replaces the cached state with the ports the chip has enabled
+            pub $($async)? fn with_device_state(mut self) -> Result<Self, I2C::Error> {
+                self.sync_from_hardware() $(.$await)??;
+                Ok(self)
+            }
+
+            $($async)? fn read_mask(&mut self) -> Result<u8, I2C::Error> {
+                let mut code = [0];
+                self.i2c_read(&mut code) $(.$await)??;
+                Ok(decode_mask::<C>(code[0]))
+            }
+
+            /// Enables only `port` and probes every address in `range` with an empty write,
+            /// reserved addresses and the multiplexer itself are skipped
+            pub $($async)? fn scan_port(
+                &mut self,
+                port: u8,
+                range: RangeInclusive<u8>,
+            ) -> Result<ScanResult, I2C::Error> {
+                self.select_only(port) $(.$await)??;
+
+                let mut found = ScanResult::new();
+                for address in range {
+                    if !SCAN_ADDRESSES.contains(&address) || address == self.address {
+                        continue;
+                    }
+                    match self.i2c.write(address, &[]) $(.$await)? {
+                        // There are only 112 addresses to find
+                        Ok(()) => found.push(address).unwrap_or_default(),
+                        Err(e) if matches!(e.kind(), ErrorKind::NoAcknowledge(_)) => {}
+                        Err(e) => return Err(MultiplexerError::Transfer(e)),
+                    }
+                }
+                Ok(found)
+            }
+
+            /// Scans every port, see [`scan_port`](Self::scan_port)
+            pub $($async)? fn scan(
+                &mut self,
+                range: RangeInclusive<u8>,
+            ) -> Result<C::Ports<ScanResult>, I2C::Error> {
+                let mut found: C::Ports<ScanResult> = PortArray::from_fn(|_| ScanResult::new());
+                for (port, devices) in found.as_mut().iter_mut().enumerate() {
+                    *devices = self.scan_port(port as u8, range.clone()) $(.$await)??;
+                }
+                Ok(found)
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Switch,
+        {
+            /// Enables all ports
+            pub $($async)? fn with_ports_enabled(self) -> Result<Self, I2C::Error> {
+                self.with_ports(all_ports::<C>()) $(.$await)?
+            }
+
+            /// Enables all ports
+            pub $($async)? fn set_ports_enabled(&mut self) -> Result<(), I2C::Error> {
+                self.set_ports(all_ports::<C>()) $(.$await)?
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: AddressPins,
+        {
+            /// Sets the address according to the enabled hardware settings
+            pub fn with_address_pins(mut self, a0: bool, a1: bool, a2: bool) -> Self {
+                self.address = address_from_pins(a0, a1, a2);
+                self
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Interrupts,
+        {
+            /// Reads which ports have their interrupt input asserted, the port states are left
+            /// untouched
+            pub $($async)? fn interrupts(&mut self) -> Result<C::Ports<bool>, I2C::Error> {
+                let mut code = [0];
+                self.i2c_read(&mut code) $(.$await)??;
+                Ok(PortArray::from_fn(|port| code[0] & (1 << (port + 4)) != 0))
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Reset,
+        {
+            /// Pulses the active low RESET pin, which returns the ports to their power-up state
+            pub $($async)? fn hard_reset(
+                &mut self,
+                reset: &mut impl OutputPin,
+                delay: &mut impl $DelayNs,
+            ) -> Result<(), I2C::Error> {
+                reset.set_low().map_err(|_| MultiplexerError::PinError)?;
+                // The chip is held in reset from here on, even if releasing the pin fails
+                self.state = C::POWER_ON_PORTS;
+                self.known = true;
+                self.dirty = false;
+                delay.delay_ns(C::RESET_PULSE_NS) $(.$await)?;
+                reset.set_high().map_err(|_| MultiplexerError::PinError)?;
+                delay.delay_ns(C::RESET_RECOVERY_NS) $(.$await)?;
+                Ok(())
+            }
+
+            /// Pulses the active low RESET pin, then enables the ports which were enabled before
+            pub $($async)? fn hard_reset_and_restore(
+                &mut self,
+                reset: &mut impl OutputPin,
+                delay: &mut impl $DelayNs,
+            ) -> Result<(), I2C::Error> {
+                let state = self.state;
+                self.hard_reset(reset, delay) $(.$await)??;
+                if state != C::POWER_ON_PORTS {
+                    self.write_state(state) $(.$await)??;
+                }
+                Ok(())
+            }
+        }
+
+        impl<I2C> Multiplexer<I2C, Pca9544>
+        where
+            I2C: $I2c,
+        {
+            /// Reads the selected port and the interrupt inputs, the port states are left
+            /// untouched
+            pub $($async)? fn read_status(&mut self) -> Result<Tca9544Status, I2C::Error> {
+                let mut code = [0];
+                self.i2c_read(&mut code) $(.$await)??;
+                Ok(Tca9544Status::from(code[0]))
+            }
+
+            /// Reads the lowest numbered port with its interrupt input asserted
+            pub $($async)? fn next_interrupting_port(&mut self) -> Result<Option<u8>, I2C::Error> {
+                Ok(self.read_status() $(.$await)??.next_interrupting_port())
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Identify,
+        {
+            /// Reads the manufacturer, part and revision through the I2C Device ID sequence
+            pub $($async)? fn device_id(&mut self) -> Result<DeviceId, I2C::Error> {
+                let mut buffer = [0; 3];
+                self.i2c
+                    .write_read(DEVICE_ID_ADDRESS, &[self.address << 1], &mut buffer)
+                    $(.$await)?
+                    .map_err(MultiplexerError::DeviceIdError)?;
+                Ok(DeviceId::from(buffer))
+            }
+
+            /// Checks the manufacturer and part of the Device ID, the revision is ignored
+            pub $($async)? fn with_device_id(mut self, expected: DeviceId) -> Result<Self, I2C::Error> {
+                let id = self.device_id() $(.$await)??;
+                if id.manufacturer != expected.manufacturer || id.part != expected.part {
+                    return Err(MultiplexerError::DeviceIdMismatch(id));
+                }
+                Ok(self)
+            }
+        }
+
+        impl<I2C, C> Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            /// Writes pending lazy changes and checks that transfers reach exactly one port
+            $($async)? fn routed(&mut self) -> Result<(), I2C::Error> {
+                self.commit() $(.$await)??;
+                if !self.known || self.state.count_ones() != 1 {
+                    return Err(MultiplexerError::AmbiguousRouting);
+                }
+                Ok(())
+            }
+        }
+
+        /// Device bound to a port and address, see [`Multiplexer::device`]
+        pub struct MuxDevice<'a, I2C, C: Chip = Pca9546> {
+            multiplexer: &'a mut Multiplexer<I2C, C>,
+            port: u8,
+            address: u8,
+        }
+
+        impl<I2C, C> MuxDevice<'_, I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            /// Port the device is behind
+            pub fn port(&self) -> u8 {
+                self.port
+            }
+
+            /// Address of the device
+            pub fn address(&self) -> u8 {
+                self.address
+            }
+
+            /// Writes `bytes` to the device
+            pub $($async)? fn write(&mut self, bytes: &[u8]) -> Result<(), I2C::Error> {
+                self.multiplexer.write_on(self.port, self.address, bytes) $(.$await)?
+            }
+
+            /// Reads `buffer` from the device
+            pub $($async)? fn read(&mut self, buffer: &mut [u8]) -> Result<(), I2C::Error> {
+                self.multiplexer.read_on(self.port, self.address, buffer) $(.$await)?
+            }
+
+            /// Writes `bytes` then reads `buffer` with a repeated start
+            pub $($async)? fn write_read(
+                &mut self,
+                bytes: &[u8],
+                buffer: &mut [u8],
+            ) -> Result<(), I2C::Error> {
+                self.multiplexer
+                    .write_read_on(self.port, self.address, bytes, buffer)
+                    $(.$await)?
+            }
+        }
+
+        impl<I2C, C> embedded_hal::i2c::ErrorType for Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            type Error = MultiplexerError<I2C::Error>;
+        }
+
+        /// Transfers go to the devices behind the only enabled port, e.g. after
+        /// [`select_only`](Multiplexer::select_only). They fail with `AmbiguousRouting` while no
+        /// port, several ports or an `Unknown` state is cached
+        impl<I2C, C> $I2c for Multiplexer<I2C, C>
+        where
+            I2C: $I2c,
+            C: Chip,
+        {
+            $($async)? fn read(
+                &mut self,
+                address: u8,
+                read: &mut [u8],
+            ) -> core::result::Result<(), Self::Error> {
+                self.routed() $(.$await)??;
+                self.i2c.read(address, read) $(.$await)?.map_err(MultiplexerError::Transfer)
+            }
+
+            $($async)? fn write(
+                &mut self,
+                address: u8,
+                write: &[u8],
+            ) -> core::result::Result<(), Self::Error> {
+                self.routed() $(.$await)??;
+                self.i2c.write(address, write) $(.$await)?.map_err(MultiplexerError::Transfer)
+            }
+
+            $($async)? fn write_read(
+                &mut self,
+                address: u8,
+                write: &[u8],
+                read: &mut [u8],
+            ) -> core::result::Result<(), Self::Error> {
+                self.routed() $(.$await)??;
+                self.i2c
+                    .write_read(address, write, read)
+                    $(.$await)?
+                    .map_err(MultiplexerError::Transfer)
+            }
+
+            $($async)? fn transaction(
+                &mut self,
+                address: u8,
+                operations: &mut [Operation<'_>],
+            ) -> core::result::Result<(), Self::Error> {
+                self.routed() $(.$await)??;
+                self.i2c
+                    .transaction(address, operations)
+                    $(.$await)?
+                    .map_err(MultiplexerError::Transfer)
+            }
+        }
+    };
+}
+
+/// Test suite run against both the blocking and the async `Multiplexer`, every test body runs
+/// inside `block_on` so the blocking expansion can share it
+#[cfg(test)]
+macro_rules! multiplexer_tests {
+    ($(.$await:ident)?; delay_ns = $delay_ns:ident) => {
+        multiplexer_tests!(
+            shared; $(.$await)?;
+            delay_ns = $delay_ns,
+            bus = core::convert::identity,
+            error = core::convert::identity,
+            eh1 = all()
+        );
+    };
+    // `bus` wraps each mock and `error` maps the mock's error kinds into the bus error. Tests
+    // behind `eh1` need a NACK kind, `any()` leaves them out
+    (
+        $name:ident; $(.$await:ident)?;
+        delay_ns = $delay_ns:ident,
+        bus = $bus:path,
+        error = $error:path,
+        eh1 = $eh1:meta
+    ) => {
+        mod $name {
+            extern crate alloc;
+            use super::Multiplexer;
+            use crate::prelude::{
+                DeviceId, MultiplexerError, Pca9544, Pca9545, Pca9546, Pca9846,
+                PortSnapshot, PortState, Tca9544Status, Tca9548,
+            };
+            use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource};
+            use alloc::vec;
+            use embassy_futures::block_on;
+            use embedded_hal_mock::eh1::delay::{CheckedDelay, Transaction as DelayTransaction};
+            use embedded_hal_mock::eh1::digital::{
+                Mock as PinMock, State as PinState, Transaction as PinTransaction,
+            };
+            use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+            use rstest::*;
+
+            #[rstest]
+            #[case([true, false, true, false], 0b0000_0101)]
+            #[case([false, true, false, true], 0b0000_1010)]
+            #[case([true; 4], 0b0000_1111)]
+            #[case([false; 4], 0b0000_0000)]
+            fn with_ports(#[case] ports: [bool; 4], #[case] result: u8) {
+                block_on(async {
+                    let i2c = Mock::new(&[Transaction::write(0x70, vec![result])]);
+                    let multiplexer = Multiplexer::new($bus(i2c)).with_ports(ports) $(.$await)?;
+                    multiplexer.unwrap().done();
+                })
+            }
+
+            #[test]
+            fn set_port() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0010]),
+                        Transaction::write(0x70, vec![0b0000_1010]),
+                        Transaction::write(0x70, vec![0b0000_1000]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(3, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(1, false) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.set_port(4, true) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn ports_enabled() {
+                block_on(async {
+                    let i2c = Mock::new(&[Transaction::write(0x70, vec![0b1111_1111])]);
+                    let multiplexer = Multiplexer::new($bus(i2c))
+                        .with_chip(Tca9548)
+                        .with_ports_enabled()
+                        $(.$await)?;
+                    multiplexer.unwrap().done();
+                })
+            }
+
+            #[test]
+            fn set_ports_enabled_disabled() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1111]),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                        Transaction::write(0x70, vec![0b0000_0010]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_ports_enabled() $(.$await)?.is_ok());
+                    assert!(multiplexer.set_ports_disabled() $(.$await)?.is_ok());
+                    // Still usable afterwards
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn single_channel_switching() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                        Transaction::write(0x70, vec![0b0000_0110]),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c)).with_chip(Pca9544);
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(2, true) $(.$await)?.is_ok());
+                    let multiplexer = multiplexer.with_ports_disabled() $(.$await)?;
+                    multiplexer.unwrap().done();
+                })
+            }
+
+            #[test]
+            fn control_register() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x71, vec![0x10, 0b0000_0001]),
+                        Transaction::write_read(0x71, vec![0x10], vec![0b0010_0001]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c))
+                        .with_chip(Pca9545)
+                        .with_address(0x71)
+                        .with_control_register(0x10);
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.interrupts() $(.$await)?,
+                        Ok([false, true, false, false])
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[rstest]
+            #[case(0b0000_0000, [false; 4])]
+            #[case(0b1111_0000, [true; 4])]
+            #[case(0b0101_0011, [true, false, true, false])]
+            fn interrupts(#[case] code: u8, #[case] result: [bool; 4]) {
+                block_on(async {
+                    let i2c = Mock::new(&[Transaction::read(0x70, vec![code])]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c)).with_chip(Pca9545);
+                    assert_eq!(multiplexer.interrupts() $(.$await)?, Ok(result));
+                    multiplexer.done();
+                })
+            }
+
+            #[rstest]
+            #[case(false, vec![Transaction::write(0x70, vec![0b0000_0010])])]
+            #[case(true, vec![
+                Transaction::write(0x70, vec![0b0000_0101]),
+                Transaction::write(0x70, vec![0b0000_0111]),
+            ])]
+            fn hard_reset(#[case] restore: bool, #[case] after: vec::Vec<Transaction>) {
+                block_on(async {
+                    let mut expectations = vec![
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        Transaction::write(0x70, vec![0b0000_0101]),
+                    ];
+                    expectations.extend(after);
+                    let mut pin = PinMock::new(&[
+                        PinTransaction::set(PinState::Low),
+                        PinTransaction::set(PinState::High),
+                    ]);
+                    let mut delay = CheckedDelay::new(&[
+                        DelayTransaction::$delay_ns(6),
+                        DelayTransaction::$delay_ns(500),
+                    ]);
+
+                    let mut multiplexer = Multiplexer::new($bus(Mock::new(&expectations)));
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(2, true) $(.$await)?.is_ok());
+                    let reset = match restore {
+                        true => multiplexer.hard_reset_and_restore(&mut pin, &mut delay) $(.$await)?,
+                        false => multiplexer.hard_reset(&mut pin, &mut delay) $(.$await)?,
+                    };
+                    assert!(reset.is_ok());
+                    // Ports 0 and 2 are only still enabled if they were restored
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+
+                    multiplexer.done();
+                    pin.done();
+                    delay.done();
+                })
+            }
+
+            #[test]
+            fn read_status() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0110]),
+                        Transaction::read(0x70, vec![0b1010_0110]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c)).with_chip(Pca9544);
+                    assert!(multiplexer.set_port(2, true) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.read_status() $(.$await)?,
+                        Ok(Tca9544Status {
+                            selected: Some(2),
+                            interrupts: [false, true, false, true],
+                        })
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn device_id() {
+                block_on(async {
+                    let i2c = Mock::new(&[Transaction::write_read(
+                        0x7C,
+                        vec![0x70 << 1],
+                        vec![0x00, 0x01, 0x0A],
+                    )]);
+                    let multiplexer = Multiplexer::new($bus(i2c))
+                        .with_chip(Pca9846)
+                        .with_device_id(DeviceId {
+                            manufacturer: 0,
+                            part: 0x21,
+                            revision: 0,
+                        })
+                        $(.$await)?;
+                    multiplexer.unwrap().done();
+                })
+            }
+
+            #[test]
+            #[cfg($eh1)]
+            fn scan_port() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                        Transaction::write(0x20, vec![]),
+                        Transaction::write(0x21, vec![])
+                            .with_error(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)),
+                        Transaction::write(0x22, vec![]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert_eq!(
+                        multiplexer.scan_port(2, 0x20..=0x22) $(.$await)?.unwrap(),
+                        [0x20, 0x22]
+                    );
+                    assert_eq!(
+                        multiplexer.scan_port(4, 0x20..=0x22) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn read_ports() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::read(0x70, vec![0b0100_0101]),
+                        Transaction::write(0x70, vec![0b0000_0010]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c)).with_chip(Pca9545);
+                    assert_eq!(
+                        multiplexer.read_ports() $(.$await)?,
+                        Ok([PortState::Enabled, PortState::Disabled, PortState::Enabled, PortState::Disabled])
+                    );
+                    // The cached state still has every port disabled
+                    assert!(multiplexer.set_port(1, PortState::Enabled) $(.$await)?.is_ok());
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn sync_from_hardware() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        // Reset behind the multiplexer's back, then port 3 enabled by someone else
+                        Transaction::read(0x70, vec![0b1000_1000]),
+                        Transaction::write(0x70, vec![0b0000_1010]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c)).with_chip(Pca9545);
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.sync_from_hardware() $(.$await)?,
+                        Ok([PortState::Disabled, PortState::Disabled, PortState::Disabled, PortState::Enabled])
+                    );
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn cached_state() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x71, vec![0b0000_0100]),
+                        Transaction::write(0x71, vec![0b0000_0101]),
+                        Transaction::write(0x71, vec![0b0000_0001]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c)).with_address(0x71);
+                    assert_eq!(multiplexer.address(), 0x71);
+                    assert_eq!(multiplexer.states(), [PortState::Unknown; 4]);
+                    assert!(multiplexer.set_port(2, true) $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.port_state(2), Ok(PortState::Enabled));
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(2, false) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.states(),
+                        [PortState::Enabled, PortState::Disabled, PortState::Disabled, PortState::Disabled]
+                    );
+                    assert_eq!(multiplexer.port_state(4), Err(MultiplexerError::InvalidPort(4)));
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn set_ports_updates_state() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1111]),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        Transaction::write(0x70, vec![0b0000_0101])
+                            .with_error(ErrorKind::Other),
+                        Transaction::write(0x70, vec![0b0000_0011]),
+                    ]);
+                    let multiplexer = Multiplexer::new($bus(i2c)).with_ports_enabled() $(.$await)?;
+                    let mut multiplexer = multiplexer.unwrap().with_ports_disabled() $(.$await)?.unwrap();
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    // A failed write leaves the cache alone
+                    assert!(multiplexer.set_ports([true, false, true, false]) $(.$await)?.is_err());
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn set_port_failure_keeps_state() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        Transaction::write(0x70, vec![0b0000_0011])
+                            .with_error(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data)),
+                        Transaction::write(0x70, vec![0b0000_0011]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_err());
+                    assert_eq!(multiplexer.port_state(1), Ok(PortState::Unknown));
+                    // Retrying writes the same byte again
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.port_state(1), Ok(PortState::Enabled));
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn toggle_port() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                        Transaction::write(0x70, vec![0b0000_0000])
+                            .with_error(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data)),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert_eq!(multiplexer.toggle_port(2) $(.$await)?, Ok(PortState::Enabled));
+                    assert!(multiplexer.toggle_port(2) $(.$await)?.is_err());
+                    assert_eq!(multiplexer.port_state(2), Ok(PortState::Unknown));
+                    assert_eq!(multiplexer.toggle_port(2) $(.$await)?, Ok(PortState::Disabled));
+                    assert_eq!(
+                        multiplexer.toggle_port(4) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn modify_ports() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1010]),
+                        Transaction::write(0x70, vec![0b0000_0111]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_ports([false, true, false, true]) $(.$await)?.is_ok());
+                    assert!(multiplexer
+                        .modify_ports(|ports| {
+                            ports[0] = true;
+                            ports[2] = true;
+                            ports[3] = false;
+                        })
+                        $(.$await)?
+                        .is_ok());
+                    assert_eq!(
+                        multiplexer.states(),
+                        [
+                            PortState::Enabled,
+                            PortState::Enabled,
+                            PortState::Enabled,
+                            PortState::Disabled
+                        ]
+                    );
+                    // Unchanged states skip the write
+                    assert!(multiplexer.modify_ports(|ports| ports[1] = true) $(.$await)?.is_ok());
+                    multiplexer.done();
+
+                    // Unknown states are always written
+                    let i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0000])]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.modify_ports(|_| ()) $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn enable_disable_ports() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1101]),
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.enable_ports(&[0, 2, 3, 0]) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.disable_ports(&[1, 4]) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    assert!(multiplexer.disable_ports(&[0, 3]) $(.$await)?.is_ok());
+                    multiplexer.done();
+                })
+            }
+
+            #[rstest]
+            #[case([true, false, true, false], 0b0000_0101)]
+            #[case([false, true, true, true], 0b0000_1110)]
+            #[case([false, false, false, false], 0b0000_0000)]
+            fn mask_round_trip(#[case] ports: [bool; 4], #[case] mask: u8) {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![mask]),
+                        Transaction::write(0x70, vec![mask]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c)).with_ports(ports) $(.$await)?.unwrap();
+                    assert_eq!(multiplexer.mask(), mask);
+                    assert!(multiplexer.set_mask(mask) $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.states(), ports.map(PortState::from));
+                    assert_eq!(
+                        multiplexer.set_mask(0b0001_0000) $(.$await)?,
+                        Err(MultiplexerError::UnsupportedPorts(0b0001_0000))
+                    );
+                    assert_eq!(multiplexer.mask(), mask);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn snapshot() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1001]),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                        Transaction::write(0x70, vec![0b0000_1001]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_ports([true, false, false, true]) $(.$await)?.is_ok());
+                    let snapshot = multiplexer.save_state();
+                    assert_eq!(u8::from(snapshot), 0b0000_1001);
+                    assert!(multiplexer.set_mask(0) $(.$await)?.is_ok());
+                    assert_ne!(multiplexer.save_state(), snapshot);
+                    assert!(multiplexer.restore_state(&snapshot) $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.save_state(), PortSnapshot::from(0b0000_1001));
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn new_from_device() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::read(0x72, vec![0b0000_0101]),
+                        Transaction::write(0x72, vec![0b0000_0111]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new_from_device($bus(i2c), 0x72) $(.$await)?.unwrap();
+                    assert_eq!(multiplexer.mask(), 0b0000_0101);
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+                    multiplexer.done();
+
+                    let mut i2c = Mock::new(&[
+                        Transaction::read(0x70, vec![0]).with_error(ErrorKind::Bus),
+                    ]);
+                    assert_eq!(
+                        Multiplexer::new_from_device($bus(&mut i2c), 0x70) $(.$await)?.err(),
+                        Some(MultiplexerError::Transfer($error(ErrorKind::Bus)))
+                    );
+                    i2c.done();
+                })
+            }
+
+            #[test]
+            fn lazy_writes() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1011])
+                            .with_error(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data)),
+                        Transaction::write(0x70, vec![0b0000_1011]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c)).with_lazy_writes();
+                    assert!(multiplexer.commit() $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.enable_ports(&[3]) $(.$await)?.is_ok());
+                    assert!(multiplexer.is_dirty());
+                    assert_eq!(multiplexer.port_state(3), Ok(PortState::Enabled));
+                    assert!(multiplexer.commit() $(.$await)?.is_err());
+                    assert!(multiplexer.is_dirty());
+                    assert!(multiplexer.commit() $(.$await)?.is_ok());
+                    assert!(!multiplexer.is_dirty());
+                    assert!(multiplexer.commit() $(.$await)?.is_ok());
+                    // Setting the committed state again doesn't mark it dirty
+                    assert!(multiplexer.set_mask(0b0000_1011) $(.$await)?.is_ok());
+                    assert!(!multiplexer.is_dirty());
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn iter_and_index() {
+                block_on(async {
+                    let i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0110])]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.iter().all(|(_, state)| state == PortState::Unknown));
+                    assert!(multiplexer.set_ports([false, true, true, false]) $(.$await)?.is_ok());
+                    let ports: vec::Vec<_> = (&multiplexer).into_iter().collect();
+                    assert_eq!(
+                        ports,
+                        [
+                            (0, PortState::Disabled),
+                            (1, PortState::Enabled),
+                            (2, PortState::Enabled),
+                            (3, PortState::Disabled)
+                        ]
+                    );
+                    assert_eq!(multiplexer[2], PortState::Enabled);
+                    assert_eq!(multiplexer.get(3), Some(PortState::Disabled));
+                    assert_eq!(multiplexer.get(4), None);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            #[should_panic(expected = "port 4 out of range")]
+            fn index_out_of_range() {
+                let multiplexer = Multiplexer::new($bus(Mock::new(&[])));
+                let _ = multiplexer[4];
+            }
+
+            #[test]
+            fn assume_state() {
+                block_on(async {
+                    let i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_0101])]);
+                    let mut multiplexer =
+                        Multiplexer::new($bus(i2c)).with_assumed_state([false, false, true, false]);
+                    assert_eq!(multiplexer.port_state(2), Ok(PortState::Enabled));
+                    // Matches the assumed state, nothing is written
+                    assert!(multiplexer.modify_ports(|ports| ports[2] = true) $(.$await)?.is_ok());
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    multiplexer.assume_state([false; 4]);
+                    assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn select_only() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1011]),
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_mask(0b0000_1011) $(.$await)?.is_ok());
+                    assert!(multiplexer.select_only(2) $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.mask(), 0b0000_0100);
+                    // Written again even though it is already selected
+                    assert!(multiplexer.select_only(2) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.select_only(4) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    assert!(multiplexer.deselect_all() $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn free() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                        Transaction::write(0x70, vec![0b0000_0000]).with_error(ErrorKind::Bus),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_port(2, true) $(.$await)?.is_ok());
+                    let (multiplexer, e) = multiplexer.deselect_and_free() $(.$await)?.err().unwrap();
+                    assert_eq!(e, MultiplexerError::Select($error(ErrorKind::Bus)));
+                    let i2c = multiplexer.deselect_and_free() $(.$await)?.ok().unwrap();
+                    let mut multiplexer = Multiplexer::new(i2c);
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    multiplexer.free().done();
+                })
+            }
+
+            #[test]
+            fn raw_control() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        Transaction::write(0x70, vec![0b1100_0010]),
+                        Transaction::read(0x70, vec![0b0100_0010]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.write_control(0b1100_0010) $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.states(), [PortState::Unknown; 4]);
+                    assert_eq!(multiplexer.read_control() $(.$await)?, Ok(0b0100_0010));
+                    multiplexer.bus_mut().done();
+                })
+            }
+
+            #[test]
+            fn set_address() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        Transaction::write(0x74, vec![0b0000_0001]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.set_address(0x80),
+                        Err(MultiplexerError::InvalidAddress(0x80))
+                    );
+                    assert_eq!(multiplexer.port_state(0), Ok(PortState::Enabled));
+                    assert!(multiplexer.set_address(0x74).is_ok());
+                    assert_eq!(multiplexer.address(), 0x74);
+                    assert_eq!(multiplexer.port_state(0), Ok(PortState::Unknown));
+                    assert!(multiplexer.modify_ports(|_| ()) $(.$await)?.is_ok());
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn port_selection() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1001]),
+                        Transaction::write(0x70, vec![0b0000_1001]),
+                        Transaction::write(0x70, vec![0b0000_1001]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_ports([true, false, false, true]) $(.$await)?.is_ok());
+                    assert!(multiplexer
+                        .set_ports([
+                            PortState::Enabled,
+                            PortState::Disabled,
+                            PortState::Unknown,
+                            PortState::Enabled
+                        ])
+                        $(.$await)?
+                        .is_ok());
+                    assert!(multiplexer.set_ports(0b0000_1001) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.set_ports([false, false, false, false, true]) $(.$await)?,
+                        Err(MultiplexerError::UnsupportedPorts(0b0001_0000))
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[rstest]
+            #[case([true, false, true, false])]
+            #[case([false, true, true, true])]
+            #[case([false; 4])]
+            fn with_state(#[case] ports: [bool; 4]) {
+                block_on(async {
+                    let code = ports
+                        .iter()
+                        .rev()
+                        .fold(0, |code, enabled| code << 1 | *enabled as u8);
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![code]),
+                        Transaction::write(0x70, vec![code]),
+                    ]);
+                    let multiplexer = Multiplexer::new($bus(i2c)).with_ports(ports) $(.$await)?.unwrap();
+                    let multiplexer = multiplexer
+                        .with_state(ports.map(PortState::from))
+                        $(.$await)?
+                        .unwrap();
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn with_all() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b1111_1111]),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                    ]);
+                    let multiplexer = Multiplexer::new($bus(i2c))
+                        .with_chip(Tca9548)
+                        .with_all(PortState::Enabled)
+                        $(.$await)?
+                        .unwrap()
+                        .with_all(PortState::Disabled)
+                        $(.$await)?
+                        .unwrap();
+                    multiplexer.done();
+
+                    let mut i2c = Mock::new(&[]);
+                    let result = Multiplexer::new($bus(&mut i2c))
+                        .with_chip(Pca9544)
+                        .with_all(PortState::Enabled)
+                        $(.$await)?;
+                    assert_eq!(result.err(), Some(MultiplexerError::UnsupportedPorts(0b0000_1111)));
+                    i2c.done();
+                })
+            }
+
+            #[test]
+            fn new_checked() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0000])
+                            .with_error(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)),
+                        Transaction::write(0x71, vec![0b0000_0000]),
+                    ]);
+                    let (i2c, e) = Multiplexer::new_checked($bus(i2c), 0x70) $(.$await)?.err().unwrap();
+                    assert_eq!(
+                        e,
+                        MultiplexerError::Select($error(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)))
+                    );
+                    let (i2c, e) = Multiplexer::new_checked(i2c, 0xF1) $(.$await)?.err().unwrap();
+                    assert_eq!(e, MultiplexerError::InvalidAddress(0xF1));
+                    let multiplexer = Multiplexer::new_checked(i2c, 0x71) $(.$await)?.ok().unwrap();
+                    assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn verification() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0010]),
+                        // Interrupt bits are ignored
+                        Transaction::read(0x70, vec![0b0100_0010]),
+                        Transaction::write(0x70, vec![0b0000_0110]),
+                        Transaction::read(0x70, vec![0b0000_0010]),
+                        Transaction::write(0x70, vec![0b0000_0110]),
+                        Transaction::read(0x70, vec![0]).with_error(ErrorKind::Bus),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c))
+                        .with_chip(Pca9545)
+                        .with_verification(true);
+                    assert!(multiplexer.set_port(1, true) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.set_ports([false, true, true, false]) $(.$await)?,
+                        Err(MultiplexerError::SelectVerificationFailed {
+                            expected: 0b0000_0110,
+                            actual: 0b0000_0010,
+                        })
+                    );
+                    assert_eq!(
+                        multiplexer.set_port(2, true) $(.$await)?,
+                        Err(MultiplexerError::Transfer($error(ErrorKind::Bus)))
+                    );
+                    assert_eq!(multiplexer.port_state(2), Ok(PortState::Unknown));
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn reset_to_default() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                        Transaction::read(0x70, vec![0b0000_0000]),
+                        // Written although the cache already claims every port is disabled
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                        Transaction::read(0x70, vec![0b0000_0001]),
+                        Transaction::write(0x70, vec![0b0000_0000]),
+                        Transaction::read(0x70, vec![0b0000_0000]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c))
+                        .with_verification(true)
+                        .with_lazy_writes();
+                    assert!(multiplexer.set_port(0, true) $(.$await)?.is_ok());
+                    assert!(multiplexer.reset_to_default() $(.$await)?.is_ok());
+                    assert!(!multiplexer.is_dirty());
+                    assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+                    assert_eq!(
+                        multiplexer.reset_to_default() $(.$await)?,
+                        Err(MultiplexerError::SelectVerificationFailed {
+                            expected: 0b0000_0000,
+                            actual: 0b0000_0001,
+                        })
+                    );
+                    assert_eq!(multiplexer.states(), [PortState::Unknown; 4]);
+                    assert!(multiplexer.reset_to_default() $(.$await)?.is_ok());
+                    assert_eq!(multiplexer.states(), [PortState::Disabled; 4]);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn exec_on() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_1000]),
+                        Transaction::transaction_start(0x20),
+                        Transaction::write(0x20, vec![0x01]),
+                        Transaction::read(0x20, vec![0x42]),
+                        Transaction::transaction_end(0x20),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    let mut read = [0];
+                    let mut operations = [
+                        embedded_hal::i2c::Operation::Write(&[0x01]),
+                        embedded_hal::i2c::Operation::Read(&mut read),
+                    ];
+                    assert!(multiplexer.exec_on(3, 0x20, &mut operations) $(.$await)?.is_ok());
+                    assert_eq!(read, [0x42]);
+                    assert_eq!(
+                        multiplexer.exec_on(4, 0x20, &mut []) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn device() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0010]),
+                        Transaction::write(0x76, vec![0xF4, 0x27]),
+                        Transaction::write(0x70, vec![0b0000_0010]),
+                        Transaction::read(0x76, vec![0x60]),
+                        Transaction::write(0x70, vec![0b0000_0010]),
+                        Transaction::write_read(0x76, vec![0xD0], vec![0x60]),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    let mut bme280 = multiplexer.device(1, 0x76);
+                    assert_eq!((bme280.port(), bme280.address()), (1, 0x76));
+                    assert!(bme280.write(&[0xF4, 0x27]) $(.$await)?.is_ok());
+                    let mut read = [0];
+                    assert!(bme280.read(&mut read) $(.$await)?.is_ok());
+                    assert!(bme280.write_read(&[0xD0], &mut read) $(.$await)?.is_ok());
+                    assert_eq!(read, [0x60]);
+                    assert_eq!(
+                        multiplexer.device(4, 0x76).write(&[0x00]) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn transfers_on() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0010]),
+                        Transaction::write(0x20, vec![0x01, 0x02]),
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                        Transaction::read(0x21, vec![0x42]),
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        Transaction::write_read(0x22, vec![0x03], vec![0x43]),
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        Transaction::write(0x20, vec![0x04]).with_error(ErrorKind::Bus),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.write_on(1, 0x20, &[0x01, 0x02]) $(.$await)?.is_ok());
+                    let mut read = [0];
+                    assert!(multiplexer.read_on(2, 0x21, &mut read) $(.$await)?.is_ok());
+                    assert_eq!(read, [0x42]);
+                    assert!(multiplexer.write_read_on(0, 0x22, &[0x03], &mut read) $(.$await)?.is_ok());
+                    assert_eq!(read, [0x43]);
+                    assert_eq!(multiplexer.states()[0], PortState::Enabled);
+                    assert_eq!(
+                        multiplexer.write_on(0, 0x20, &[0x04]) $(.$await)?,
+                        Err(MultiplexerError::Transfer($error(ErrorKind::Bus)))
+                    );
+                    assert_eq!(
+                        multiplexer.read_on(4, 0x21, &mut read) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn transaction_on() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0100]),
+                        Transaction::transaction_start(0x20),
+                        Transaction::write(0x20, vec![0x10]),
+                        Transaction::read(0x20, vec![0x0F]),
+                        Transaction::write(0x20, vec![0x10, 0x8F]),
+                        Transaction::transaction_end(0x20),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.transaction_on(1, 0x20, &mut []) $(.$await)?.is_ok());
+                    assert_eq!(
+                        multiplexer.transaction_on(4, 0x20, &mut []) $(.$await)?,
+                        Err(MultiplexerError::InvalidPort(4))
+                    );
+                    let mut read = [0];
+                    let mut operations = [
+                        embedded_hal::i2c::Operation::Write(&[0x10]),
+                        embedded_hal::i2c::Operation::Read(&mut read),
+                        embedded_hal::i2c::Operation::Write(&[0x10, 0x8F]),
+                    ];
+                    assert!(multiplexer.transaction_on(2, 0x20, &mut operations) $(.$await)?.is_ok());
+                    assert_eq!(read, [0x0F]);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn scan_bus_error() {
+                block_on(async {
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x70, vec![0b0000_0001]),
+                        Transaction::write(0x20, vec![]).with_error(ErrorKind::Bus),
+                    ]);
+                    let mut multiplexer = Multiplexer::new($bus(i2c));
+                    assert_eq!(
+                        multiplexer.scan_port(0, 0x20..=0x22) $(.$await)?,
+                        Err(MultiplexerError::Transfer($error(ErrorKind::Bus)))
+                    );
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            #[cfg($eh1)]
+            fn scan() {
+                block_on(async {
+                    let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+                    let i2c = Mock::new(&[
+                        Transaction::write(0x77, vec![0b0000_0001]),
+                        Transaction::write(0x76, vec![]).with_error(nack),
+                        Transaction::write(0x77, vec![0b0000_0010]),
+                        Transaction::write(0x76, vec![]),
+                    ]);
+                    // Reserved addresses and the multiplexer itself are never probed
+                    let mut multiplexer = Multiplexer::new($bus(i2c))
+                        .with_chip(crate::prelude::Pca9543)
+                        .with_address(0x77);
+                    let [port0, port1] = multiplexer.scan(0x76..=0x7F) $(.$await)?.unwrap();
+                    assert!(port0.is_empty());
+                    assert_eq!(port1, [0x76]);
+                    multiplexer.done();
+                })
+            }
+
+            #[test]
+            fn default_chip() {
+                block_on(async {
+                    let i2c = Mock::new(&[Transaction::write(0x70, vec![0b0000_1000])]);
+                    let mut multiplexer: Multiplexer<_, Pca9546> = Multiplexer::new($bus(i2c));
+                    assert!(multiplexer.set_port(3, true) $(.$await)?.is_ok());
+                    multiplexer.done();
+                })
+            }
+        }
+    };
+}
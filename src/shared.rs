@@ -0,0 +1,188 @@
+//! Blocking `Multiplexer` shared between per port I2C handles
+//!
+//! [`Multiplexer::split`] moves the multiplexer into a [`SharedMultiplexer`] whose ports each
+//! implement `embedded_hal::i2c::I2c`, so every driver can own a handle without
+//! `embedded-hal-bus` or a user managed `RefCell`.
+
+use crate::chip::{Chip, PortArray};
+use crate::error::MultiplexerError;
+use crate::Multiplexer;
+use core::cell::RefCell;
+use embedded_hal::i2c::{ErrorType, I2c, Operation, SevenBitAddress};
+
+impl<I2C, C> Multiplexer<I2C, C>
+where
+    I2C: I2c,
+    C: Chip,
+{
+    /// Moves the multiplexer into a [`SharedMultiplexer`] handing out an I2C handle per port
+    pub fn split(self) -> SharedMultiplexer<I2C, C> {
+        SharedMultiplexer {
+            multiplexer: RefCell::new(self),
+        }
+    }
+}
+
+/// Multiplexer shared by its [`SharedPort`]s, they keep using its cached state so a port which
+/// is already the only one enabled isn't selected again
+pub struct SharedMultiplexer<I2C, C: Chip> {
+    multiplexer: RefCell<Multiplexer<I2C, C>>,
+}
+
+impl<I2C, C> SharedMultiplexer<I2C, C>
+where
+    I2C: I2c,
+    C: Chip,
+{
+    /// Creates the handle for `port`
+    pub fn port(&self, port: u8) -> Result<SharedPort<'_, I2C, C>, MultiplexerError<I2C::Error>> {
+        if port >= C::CHANNELS {
+            return Err(MultiplexerError::InvalidPort(port));
+        }
+        Ok(SharedPort {
+            multiplexer: &self.multiplexer,
+            port,
+        })
+    }
+
+    /// Creates the handle for every port
+    pub fn ports(&self) -> C::Ports<SharedPort<'_, I2C, C>> {
+        PortArray::from_fn(|port| SharedPort {
+            multiplexer: &self.multiplexer,
+            port: port as u8,
+        })
+    }
+
+    /// Returns the multiplexer once no handle is left
+    pub fn into_inner(self) -> Multiplexer<I2C, C> {
+        self.multiplexer.into_inner()
+    }
+}
+
+/// I2C handle for the devices behind one port, the port is selected exclusively before every
+/// operation unless it already is
+pub struct SharedPort<'a, I2C, C: Chip> {
+    multiplexer: &'a RefCell<Multiplexer<I2C, C>>,
+    port: u8,
+}
+
+impl<I2C, C> SharedPort<'_, I2C, C>
+where
+    I2C: I2c,
+    C: Chip,
+{
+    fn run<T>(
+        &mut self,
+        f: impl FnOnce(&mut I2C) -> Result<T, I2C::Error>,
+    ) -> Result<T, MultiplexerError<I2C::Error>> {
+        let mut multiplexer = self.multiplexer.borrow_mut();
+        if !multiplexer.known || multiplexer.dirty || multiplexer.state != 1 << self.port {
+            multiplexer.select_only(self.port)?;
+        }
+        f(&mut multiplexer.i2c).map_err(MultiplexerError::Transfer)
+    }
+}
+
+impl<I2C, C> ErrorType for SharedPort<'_, I2C, C>
+where
+    I2C: I2c,
+    C: Chip,
+{
+    type Error = MultiplexerError<I2C::Error>;
+}
+
+impl<I2C, C> I2c for SharedPort<'_, I2C, C>
+where
+    I2C: I2c,
+    C: Chip,
+{
+    fn read(&mut self, address: SevenBitAddress, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.run(|i2c| i2c.read(address, read))
+    }
+
+    fn write(&mut self, address: SevenBitAddress, write: &[u8]) -> Result<(), Self::Error> {
+        self.run(|i2c| i2c.write(address, write))
+    }
+
+    fn write_read(
+        &mut self,
+        address: SevenBitAddress,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.run(|i2c| i2c.write_read(address, write, read))
+    }
+
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.run(|i2c| i2c.transaction(address, operations))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use crate::prelude::*;
+    use alloc::vec;
+    use embedded_hal::i2c::{ErrorKind, I2c};
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+
+    #[test]
+    fn interleaved_ports() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write(0x48, vec![0x01]),
+            Transaction::write(0x70, vec![0b0000_0100]),
+            Transaction::write(0x48, vec![0x02]),
+            Transaction::read(0x48, vec![0x03]),
+            Transaction::write(0x70, vec![0b0000_0001]),
+            Transaction::write_read(0x48, vec![0x04], vec![0x05]),
+        ]);
+        let shared = Multiplexer::new(i2c).split();
+        let [mut first, _, mut third, _] = shared.ports();
+        assert!(first.write(0x48, &[0x01]).is_ok());
+        assert!(third.write(0x48, &[0x02]).is_ok());
+        // Already selected
+        let mut read = [0];
+        assert!(third.read(0x48, &mut read).is_ok());
+        assert_eq!(read, [0x03]);
+        assert!(first.write_read(0x48, &[0x04], &mut read).is_ok());
+        assert_eq!(read, [0x05]);
+        let multiplexer = shared.into_inner();
+        assert_eq!(
+            multiplexer.states(),
+            [
+                PortState::Enabled,
+                PortState::Disabled,
+                PortState::Disabled,
+                PortState::Disabled
+            ]
+        );
+        multiplexer.done();
+    }
+
+    #[test]
+    fn select_error() {
+        let i2c = Mock::new(&[
+            Transaction::write(0x70, vec![0b0000_0010]).with_error(ErrorKind::Bus),
+            Transaction::write(0x70, vec![0b0000_0010]),
+            Transaction::write(0x48, vec![0x01]),
+        ]);
+        let shared = Multiplexer::new(i2c).split();
+        let mut port = shared.port(1).unwrap();
+        assert_eq!(
+            port.write(0x48, &[0x01]),
+            Err(MultiplexerError::Select(ErrorKind::Bus))
+        );
+        // The failed select is retried
+        assert!(port.write(0x48, &[0x01]).is_ok());
+        assert!(matches!(
+            shared.port(4),
+            Err(MultiplexerError::InvalidPort(4))
+        ));
+        shared.into_inner().done();
+    }
+}